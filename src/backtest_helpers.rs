@@ -0,0 +1,385 @@
+use crate::engine::{Engine, DEFAULT_NAMESPACE};
+use crate::io_helpers::{open_input, BacktestReport, BacktestTrade, LineParser, Response};
+use crate::log_helpers::log_warn;
+use crate::{Message, Vertex};
+use chrono::{DateTime, FixedOffset};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::BufRead;
+
+pub const DEFAULT_BACKTEST_SIZE: f64 = 1.0;
+pub const DEFAULT_MAX_HOPS: usize = 4;
+
+/* How the backtest command trades. size is the amount put through each cycle, in the currency
+ * the cycle starts from. latency is how long after detecting a cycle the trade goes through,
+ * at whatever the rates are by then. A cycle is only an opportunity if it returns more than
+ * 1 + min_profit, and only cycles of up to max_hops edges are looked for.
+ */
+#[derive(Clone, Debug)]
+pub struct BacktestOptions {
+    pub size: f64,
+    pub latency: chrono::Duration,
+    pub max_hops: usize,
+    pub min_profit: f64,
+}
+
+pub fn validate_size(key: &str, size: Option<f64>) -> Result<(), String> {
+    match size {
+        Some(s) if !s.is_finite() || s <= 0.0 => {
+            Err(format!("{} must be positive, got {}", key, s))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn validate_max_hops(key: &str, max_hops: Option<usize>) -> Result<(), String> {
+    /* A cycle needs at least two edges, there and back.
+     */
+    match max_hops {
+        Some(n) if n < 2 => Err(format!("{} must be at least 2, got {}", key, n)),
+        _ => Ok(()),
+    }
+}
+
+pub fn validate_min_profit(key: &str, min_profit: Option<f64>) -> Result<(), String> {
+    match min_profit {
+        Some(p) if !p.is_finite() || p < 0.0 => {
+            Err(format!("{} must be at least 0, got {}", key, p))
+        }
+        _ => Ok(()),
+    }
+}
+
+/* A profitable cycle and when it was first seen.
+ */
+struct Opportunity {
+    path: Vec<Vertex>,
+    opened: DateTime<FixedOffset>,
+}
+
+/* A trade waiting for its latency to pass.
+ */
+struct PendingTrade {
+    namespace: String,
+    detected: DateTime<FixedOffset>,
+    execute_at: DateTime<FixedOffset>,
+    path: Vec<Vertex>,
+    expected_rate: f64,
+}
+
+struct Backtest<'a> {
+    engine: &'a mut Engine,
+    options: &'a BacktestOptions,
+    report: BacktestReport,
+    pending: VecDeque<PendingTrade>,
+    // Open opportunities by namespace and cycle, with the time each one opened.
+    open: BTreeMap<(String, Vec<String>), Opportunity>,
+    now: Option<DateTime<FixedOffset>>,
+}
+
+pub fn backtest(
+    paths: &[String],
+    engine: &mut Engine,
+    options: &BacktestOptions,
+) -> Result<BacktestReport, String> {
+    /* Feeds the recorded files through the engine one line at a time and looks for a profitable
+     * cycle through the pair each price update touches, through the edges out of an exchange
+     * that resumes, and through every edge after the configuration is reloaded. Every new
+     * opportunity is traded once, after the latency, and followed until a later update or halt
+     * makes it unprofitable. The trades are assumed to be too small to move the prices.
+     * Requests and other commands in the files are skipped.
+     */
+    let mut backtest = Backtest::new(engine, options);
+
+    let mut namespace = DEFAULT_NAMESPACE.to_string();
+    for path in paths {
+        let reader = open_input(path)?;
+        let mut parser = LineParser::new();
+        for (line_number, line) in reader.lines().enumerate() {
+            let input_string = line.map_err(|e| format!("Error reading {}: {}", path, e))?;
+            let result = match parser.parse(&input_string) {
+                Ok(Some(message)) => backtest.handle_message(&mut namespace, message),
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                log_warn(&format!("{}:{}: {}", path, line_number + 1, e));
+            }
+        }
+        if let Err(e) = parser.finish() {
+            log_warn(&format!("{}: {}", path, e));
+        }
+    }
+    return backtest.finish();
+}
+
+impl<'a> Backtest<'a> {
+    fn new(engine: &'a mut Engine, options: &'a BacktestOptions) -> Backtest<'a> {
+        return Backtest {
+            engine,
+            options,
+            report: BacktestReport {
+                size: options.size,
+                ..BacktestReport::default()
+            },
+            pending: VecDeque::new(),
+            open: BTreeMap::new(),
+            now: None,
+        };
+    }
+
+    fn finish(mut self) -> Result<BacktestReport, String> {
+        // Trades still waiting go through at the rates left at the end of the data.
+        while let Some(trade) = self.pending.pop_front() {
+            self.execute(trade)?;
+        }
+        self.report.open = self.open.len();
+        return Ok(self.report);
+    }
+
+    fn handle_message(&mut self, namespace: &mut String, message: Message) -> Result<(), String> {
+        match message {
+            Message::PriceUpdate(update) => {
+                self.advance(update.timestamp)?;
+                self.engine.apply_price_update(namespace, &update)?;
+                self.report.updates += 1;
+                self.close_opportunities()?;
+                let source = Vertex {
+                    exchange: update.exchange.clone(),
+                    currency: update.source_currency.clone(),
+                };
+                let destination = Vertex {
+                    exchange: update.exchange.clone(),
+                    currency: update.destination_currency.clone(),
+                };
+                self.detect(namespace, &source, &destination)?;
+                self.detect(namespace, &destination, &source)?;
+            }
+            Message::PairRemove(removal) => {
+                self.advance(removal.timestamp)?;
+                self.engine
+                    .handle_message(namespace, Message::PairRemove(removal))?;
+                self.close_opportunities()?;
+            }
+            Message::ExchangeResume(exchange) => {
+                self.engine
+                    .handle_message(namespace, Message::ExchangeResume(exchange.clone()))?;
+                self.detect_from(namespace, Some(&exchange))?;
+            }
+            Message::ConfigReload => {
+                // Fees and transfer costs may have changed the rate of any edge.
+                self.engine
+                    .handle_message(namespace, Message::ConfigReload)?;
+                self.close_opportunities()?;
+                self.detect_from(namespace, None)?;
+            }
+            Message::VertexRemove(_) | Message::ExchangeHalt(_) | Message::Use(_) => {
+                if let Some(Response::Using(name)) =
+                    self.engine.handle_message(namespace, message)?
+                {
+                    *namespace = name;
+                }
+                self.close_opportunities()?;
+            }
+            _ => {}
+        }
+        return Ok(());
+    }
+
+    fn advance(&mut self, timestamp: DateTime<FixedOffset>) -> Result<(), String> {
+        /* Moves the clock on to the timestamp of the next line, first executing the trades
+         * that were due before it, at the rates they would have seen.
+         *
+         * A trade due at time t sees every update stamped at or before t. So a trade due at
+         * exactly the timestamp of this line waits until the line has been applied, and goes
+         * through before the next line with a later timestamp.
+         */
+        let now = match self.now {
+            Some(now) if now > timestamp => now,
+            _ => timestamp,
+        };
+        while self
+            .pending
+            .front()
+            .is_some_and(|trade| trade.execute_at < now)
+        {
+            if let Some(trade) = self.pending.pop_front() {
+                self.execute(trade)?;
+            }
+        }
+        self.now = Some(now);
+        return Ok(());
+    }
+
+    fn detect(
+        &mut self,
+        namespace: &str,
+        source: &Vertex,
+        destination: &Vertex,
+    ) -> Result<(), String> {
+        let cycle =
+            match self
+                .engine
+                .best_cycle(namespace, source, destination, self.options.max_hops)?
+            {
+                Some(cycle) if cycle.rate > 1.0 + self.options.min_profit => cycle,
+                _ => return Ok(()),
+            };
+        let key = (namespace.to_string(), cycle_key(&cycle.path));
+        let now = match self.now {
+            Some(now) if !self.open.contains_key(&key) => now,
+            _ => return Ok(()),
+        };
+        self.open.insert(
+            key,
+            Opportunity {
+                path: cycle.path.clone(),
+                opened: now,
+            },
+        );
+        self.report.opportunities += 1;
+
+        let trade = PendingTrade {
+            namespace: namespace.to_string(),
+            detected: now,
+            execute_at: now + self.options.latency,
+            path: cycle.path,
+            expected_rate: cycle.rate,
+        };
+        if trade.execute_at <= now {
+            return self.execute(trade);
+        }
+        self.pending.push_back(trade);
+        return Ok(());
+    }
+
+    fn detect_from(&mut self, namespace: &str, exchange: Option<&str>) -> Result<(), String> {
+        /* Looks for a cycle starting with each edge out of the exchange's vertices, or out of
+         * every vertex if no exchange is given.
+         */
+        let edges = self.engine.edges(namespace, exchange)?;
+        for edge in edges {
+            let leaves = exchange.is_none_or(|exchange| edge.source.exchange == exchange);
+            if leaves && edge.source != edge.destination {
+                self.detect(namespace, &edge.source, &edge.destination)?;
+            }
+        }
+        return Ok(());
+    }
+
+    fn close_opportunities(&mut self) -> Result<(), String> {
+        /* Closes the open opportunities that are no longer profitable and records how long
+         * each one lasted.
+         */
+        let now = match self.now {
+            Some(now) => now,
+            None => return Ok(()),
+        };
+        let mut closed = Vec::new();
+        for (key, opportunity) in &self.open {
+            let rate = self.engine.cycle_rate(&key.0, &opportunity.path)?;
+            if rate.is_none_or(|r| r <= 1.0 + self.options.min_profit) {
+                closed.push((key.clone(), opportunity.opened));
+            }
+        }
+        for (key, opened) in closed {
+            self.open.remove(&key);
+            self.report.durations.push(now - opened);
+        }
+        return Ok(());
+    }
+
+    fn execute(&mut self, trade: PendingTrade) -> Result<(), String> {
+        /* Puts size through the cycle at the current rates. If the cycle is broken by now the
+         * trade is missed and makes nothing.
+         */
+        let executed_rate = self.engine.cycle_rate(&trade.namespace, &trade.path)?;
+        let pnl = executed_rate.map_or(0.0, |rate| self.options.size * (rate - 1.0));
+        let currency = trade.path[0].currency.clone();
+        *self.report.pnl.entry(currency).or_insert(0.0) += pnl;
+        self.report.trades.push(BacktestTrade {
+            detected: trade.detected,
+            executed: trade.execute_at,
+            path: trade.path,
+            expected_rate: trade.expected_rate,
+            executed_rate,
+            pnl,
+        });
+        return Ok(());
+    }
+}
+
+fn cycle_key(path: &[Vertex]) -> Vec<String> {
+    /* The same cycle can be found starting from any of its vertices, so it is keyed by the
+     * rotation that starts from its smallest vertex.
+     */
+    let names: Vec<String> = path
+        .iter()
+        .map(|v| format!("{} {}", v.exchange, v.currency))
+        .collect();
+    let start = (0..names.len()).min_by_key(|i| &names[*i]).unwrap_or(0);
+    return names[start..]
+        .iter()
+        .chain(names[..start].iter())
+        .cloned()
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io_helpers::parse_line;
+
+    fn run(lines: &[&str], latency_secs: i64) -> BacktestReport {
+        let mut engine = Engine::new();
+        let options = BacktestOptions {
+            size: 1.0,
+            latency: chrono::Duration::seconds(latency_secs),
+            max_hops: DEFAULT_MAX_HOPS,
+            min_profit: 0.0,
+        };
+        let mut backtest = Backtest::new(&mut engine, &options);
+        let mut namespace = DEFAULT_NAMESPACE.to_string();
+        for line in lines {
+            let message = parse_line(line).unwrap().unwrap();
+            backtest.handle_message(&mut namespace, message).unwrap();
+        }
+        return backtest.finish().unwrap();
+    }
+
+    // Selling BTC on KRAKEN and buying it back on GDAX makes 9%, once both are trading.
+    const HALTED_ARBITRAGE: &[&str] = &[
+        "2017-11-01T09:42:20+00:00 KRAKEN BTC USD 1000.0 0.00099",
+        "EXCHANGE_HALT KRAKEN",
+        "2017-11-01T09:42:21+00:00 KRAKEN BTC USD 1100.0 0.0009",
+        "2017-11-01T09:42:22+00:00 GDAX BTC USD 1000.0 0.00099",
+    ];
+
+    #[test]
+    fn resuming_an_exchange_looks_for_cycles_through_it() {
+        assert_eq!(run(HALTED_ARBITRAGE, 0).opportunities, 0);
+
+        let mut lines = HALTED_ARBITRAGE.to_vec();
+        lines.push("EXCHANGE_RESUME KRAKEN");
+        let report = run(&lines, 0);
+        assert_eq!(report.opportunities, 1);
+        assert_eq!(report.trades.len(), 1);
+        assert!(report.trades[0].pnl > 0.08);
+    }
+
+    #[test]
+    fn a_trade_due_at_an_update_sees_that_update() {
+        let report = run(
+            &[
+                "2017-11-01T09:42:21+00:00 KRAKEN BTC USD 1100.0 0.0009",
+                "2017-11-01T09:42:22+00:00 GDAX BTC USD 1000.0 0.00099",
+                "2017-11-01T09:42:27+00:00 KRAKEN BTC USD 1000.0 0.00099",
+                "2017-11-01T09:42:30+00:00 GDAX BTC USD 1000.0 0.00099",
+            ],
+            5,
+        );
+        assert_eq!(report.trades.len(), 1);
+        let trade = &report.trades[0];
+        assert!(trade.expected_rate > 1.08);
+        assert!((trade.executed_rate.unwrap() - 0.99).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,198 @@
+use crate::engine::{Engine, DEFAULT_NAMESPACE};
+use crate::io_helpers::Response;
+use crate::modified_floyd_warshall_helpers::{floyd_warshall_dense, NO_NEXT};
+use crate::{Message, PriceUpdate};
+use chrono::{FixedOffset, TimeZone};
+use std::time::{Duration, Instant};
+
+/* What the bench command measures.
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BenchKind {
+    FloydWarshall,
+    Updates,
+}
+
+pub fn parse_bench_kind(input: &str) -> Result<BenchKind, String> {
+    match input.to_ascii_lowercase().as_str() {
+        "floyd-warshall" | "floyd_warshall" => Ok(BenchKind::FloydWarshall),
+        "updates" => Ok(BenchKind::Updates),
+        _ => Err(format!(
+            "Unknown benchmark '{}'. Expected one of: floyd-warshall, updates",
+            input
+        )),
+    }
+}
+
+const BENCH_CURRENCIES: usize = 10;
+const UPDATES_PER_ITERATION: usize = 10000;
+
+/* A small xorshift generator so the benchmark doesn't need an extra dependency and gives the
+ * same graph for the same seed.
+ */
+pub struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    pub fn new(seed: u64) -> XorShift {
+        return XorShift { state: seed.max(1) };
+    }
+
+    pub fn next_f64(&mut self) -> f64 {
+        /* Returns a number in [0, 1).
+         */
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        return (self.state >> 11) as f64 / (1u64 << 53) as f64;
+    }
+}
+
+pub fn random_rate_table(n: usize, density: f64, seed: u64) -> (Vec<f32>, Vec<usize>) {
+    /* Builds dense rate and next tables for n vertices. Each vertex gets a value and the rate
+     * from i to j is value_i / value_j less a small spread, so there are no arbitrage cycles.
+     * Roughly `density` of the pairs have an edge.
+     */
+    let mut rng = XorShift::new(seed);
+    let values: Vec<f64> = (0..n).map(|_| 0.01 + rng.next_f64() * 1000.0).collect();
+    let mut rate = vec![0.0f32; n * n];
+    let mut next = vec![NO_NEXT; n * n];
+    for i in 0..n {
+        for j in 0..n {
+            if i == j {
+                rate[i * n + j] = 1.0;
+                next[i * n + j] = j;
+            } else if rng.next_f64() < density {
+                let spread = 0.001 + rng.next_f64() * 0.01;
+                rate[i * n + j] = (values[i] / values[j] * (1.0 - spread)) as f32;
+                next[i * n + j] = j;
+            }
+        }
+    }
+    return (rate, next);
+}
+
+pub fn bench_floyd_warshall(vertices: usize, threads: usize, iterations: usize, seed: u64) {
+    /* Times the sequential and parallel Floyd-Warshall on the same random graph and checks
+     * that both give bit-for-bit identical tables.
+     */
+    let (rate, next) = random_rate_table(vertices, 0.1, seed);
+    let iterations = iterations.max(1);
+
+    let run = |threads: usize| -> (Duration, Vec<f32>, Vec<usize>) {
+        let mut total = Duration::from_secs(0);
+        let mut result = (rate.clone(), next.clone());
+        for _ in 0..iterations {
+            let mut rate_out = rate.clone();
+            let mut next_out = next.clone();
+            let started = Instant::now();
+            floyd_warshall_dense(&mut rate_out, &mut next_out, vertices, threads);
+            total += started.elapsed();
+            result = (rate_out, next_out);
+        }
+        return (total / iterations as u32, result.0, result.1);
+    };
+
+    let (sequential, rate_seq, next_seq) = run(1);
+    let (parallel, rate_par, next_par) = run(threads);
+
+    let identical = next_seq == next_par
+        && rate_seq
+            .iter()
+            .zip(rate_par.iter())
+            .all(|(a, b)| a.to_bits() == b.to_bits());
+
+    println!(
+        "BENCH floyd_warshall vertices={} threads={} iterations={}",
+        vertices, threads, iterations
+    );
+    println!("sequential {:.3} ms", millis(sequential));
+    println!("parallel   {:.3} ms", millis(parallel));
+    println!(
+        "speedup    {:.2}x",
+        millis(sequential) / millis(parallel).max(1e-9)
+    );
+    println!("identical  {}", identical);
+}
+
+pub fn bench_updates(vertices: usize, iterations: usize, seed: u64) {
+    /* Times price updates against graphs of growing size, up to the given number of vertices.
+     * Each graph has ten currencies per exchange and grows by adding exchanges. An update only
+     * touches the edges of its own pair, so the time per update should stay about the same
+     * however large the graph gets.
+     */
+    let iterations = iterations.max(1);
+    let largest = vertices.max(BENCH_CURRENCIES * 2);
+    println!(
+        "BENCH updates vertices={} iterations={} updates_per_iteration={}",
+        largest, iterations, UPDATES_PER_ITERATION
+    );
+    println!("vertices  edges     per update");
+    for size in [largest / 8, largest / 4, largest / 2, largest] {
+        let exchanges = (size / BENCH_CURRENCIES).max(2);
+        let mut rng = XorShift::new(seed);
+        let mut engine = Engine::new();
+        let mut clock = 0;
+
+        // Every exchange quotes a chain through its currencies plus a few random pairs.
+        for exchange in 0..exchanges {
+            for currency in 1..BENCH_CURRENCIES {
+                let update = random_update(&mut rng, exchange, currency - 1, currency, clock);
+                let _ = engine.apply_price_update(DEFAULT_NAMESPACE, &update);
+                clock += 1;
+            }
+        }
+
+        let mut total = Duration::from_secs(0);
+        for _ in 0..iterations {
+            let updates: Vec<PriceUpdate> = (0..UPDATES_PER_ITERATION)
+                .map(|i| {
+                    let exchange = (rng.next_f64() * exchanges as f64) as usize;
+                    let source = (rng.next_f64() * BENCH_CURRENCIES as f64) as usize;
+                    let offset = 1 + (rng.next_f64() * (BENCH_CURRENCIES - 1) as f64) as usize;
+                    let destination = (source + offset) % BENCH_CURRENCIES;
+                    return random_update(&mut rng, exchange, source, destination, clock + i);
+                })
+                .collect();
+            clock += UPDATES_PER_ITERATION;
+            let started = Instant::now();
+            for update in &updates {
+                let _ = engine.apply_price_update(DEFAULT_NAMESPACE, update);
+            }
+            total += started.elapsed();
+        }
+        let stats = match engine.handle_message(DEFAULT_NAMESPACE, Message::Stats) {
+            Ok(Some(Response::Stats(stats))) => stats,
+            _ => continue,
+        };
+        let per_update = total.as_secs_f64() * 1e6 / (iterations * UPDATES_PER_ITERATION) as f64;
+        println!(
+            "{:<9} {:<9} {:.3} us",
+            stats.vertices, stats.edges, per_update
+        );
+    }
+}
+
+fn random_update(
+    rng: &mut XorShift,
+    exchange: usize,
+    source: usize,
+    destination: usize,
+    clock: usize,
+) -> PriceUpdate {
+    let rate = 0.5 + rng.next_f64();
+    return PriceUpdate {
+        // Seconds after 2017-11-01T00:00:00+00:00, so each update is newer than the last.
+        timestamp: FixedOffset::east(0).timestamp(1_509_494_400 + clock as i64, 0),
+        exchange: format!("EX{}", exchange),
+        source_currency: format!("C{}", source),
+        destination_currency: format!("C{}", destination),
+        forward_factor: (rate * 0.999) as f32,
+        backward_factor: (0.999 / rate) as f32,
+    };
+}
+
+pub fn millis(d: Duration) -> f64 {
+    return d.as_secs_f64() * 1000.0;
+}
@@ -0,0 +1,352 @@
+use crate::backtest_helpers::{validate_max_hops, validate_min_profit, validate_size};
+use crate::bench_helpers::{parse_bench_kind, BenchKind};
+use crate::io_helpers::{parse_output_format, OutputFormat};
+use crate::log_helpers::{parse_log_level, LogLevel};
+use crate::matrix_helpers::{parse_numeric_mode, NumericMode};
+use crate::pipeline_helpers::{parse_backpressure, Backpressure};
+use crate::replay_helpers::{parse_replay_speed, ReplaySpeed};
+
+pub const USAGE: &str = "Usage: tenx_test [COMMAND] [OPTIONS]
+
+Commands:
+  run       Read price updates and requests and answer the requests (default)
+  serve     Accept price updates and requests over TCP
+  replay    Feed recorded files through the engine, optionally at their recorded pace
+  check     Validate input lines without answering requests
+  bench     Benchmark Floyd-Warshall or price updates on a random graph
+  backtest  Replay recorded files and simulate trading the arbitrage cycles they contain
+
+Options:
+  -i, --input <file>...     Read input from the given files ('-' for stdin). Defaults to stdin
+  -o, --output <file>       Write results to a file instead of stdout
+  -f, --format <format>     Output format: text, jsonl or csv. Defaults to text
+  -q, --quiet               Suppress the interactive prompt and informational messages
+  -c, --config <file>       Load settings from a TOML configuration file
+      --log-level <level>   Log level for stderr: error, warn, info or debug. Defaults to warn
+      --listen <address>    Address for the serve command. Defaults to 127.0.0.1:7878
+      --metrics-listen <address>
+                            Serve Prometheus metrics on http://<address>/metrics
+      --threads <n>         Threads for Floyd-Warshall on large graphs. Defaults to all cores
+      --numeric <type>      Floyd-Warshall arithmetic: f32 or log-f64. Defaults to f32
+      --detailed            List every hop of a best rate path with its kind, rate and age
+      --verify              Re-derive every best rate from its path and flag mismatches
+      --backpressure <policy>
+                            What a full input queue does with price updates: block,
+                            drop-oldest or coalesce. Defaults to block
+      --queue-capacity <n>  Messages the input queue holds. Defaults to 1024
+      --coalesce-window <n> Most queued price updates to coalesce and apply together.
+                            0 or 1 turns coalescing off. Defaults to 256
+      --bench <kind>        What bench measures: floyd-warshall compares sequential and
+                            parallel runs, updates times price updates as the graph grows.
+                            Defaults to floyd-warshall
+      --speed <speed>       How fast replay feeds the files: max, realtime or a factor of
+                            real time such as 10x. Defaults to max
+      --fee <fraction>      Trading fee on every exchange without its own, e.g. 0.001.
+                            Overrides fees.default in the configuration file
+      --size <amount>       Amount backtest puts through each cycle, in the currency the
+                            cycle starts from. Defaults to 1
+      --latency-ms <ms>     How long backtest takes to execute a cycle after detecting it.
+                            Defaults to 0
+      --max-hops <n>        Longest cycle backtest looks for, in edges. Defaults to 4
+      --min-profit <fraction>
+                            Smallest return above 1 that backtest trades on. Defaults to 0
+      --vertices <n>        Number of vertices for bench. Defaults to 300
+      --iterations <n>      Number of runs to average for bench. Defaults to 3
+      --seed <n>            Seed for the bench graph. Defaults to 1
+  -h, --help                Print this message";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Command {
+    Run,
+    Serve,
+    Replay,
+    Check,
+    Bench,
+    Backtest,
+}
+
+/* The parsed command line. Options that can also come from the configuration file are kept
+ * as Options so that the command line only overrides what was actually given.
+ */
+#[derive(Clone, Debug)]
+pub struct Cli {
+    pub command: Command,
+    pub inputs: Vec<String>,
+    pub output: Option<String>,
+    pub format: Option<OutputFormat>,
+    pub quiet: bool,
+    pub config: Option<String>,
+    pub log_level: Option<LogLevel>,
+    pub listen: Option<String>,
+    pub metrics_listen: Option<String>,
+    pub threads: Option<usize>,
+    pub numeric: Option<NumericMode>,
+    pub verify: bool,
+    pub detailed: bool,
+    pub backpressure: Option<Backpressure>,
+    pub queue_capacity: Option<usize>,
+    pub coalesce_window: Option<usize>,
+    pub speed: Option<ReplaySpeed>,
+    pub fee: Option<f64>,
+    pub size: Option<f64>,
+    pub latency_ms: Option<u64>,
+    pub max_hops: Option<usize>,
+    pub min_profit: Option<f64>,
+    pub bench: BenchKind,
+    pub vertices: usize,
+    pub iterations: usize,
+    pub seed: u64,
+    pub help: bool,
+}
+
+pub fn parse_args(args: &[String]) -> Result<Cli, String> {
+    /* Parses the command line arguments (excluding the program name) into a Cli.
+     * The command is optional and defaults to run so that `cargo run` keeps working as before.
+     */
+    let mut cli = Cli {
+        command: Command::Run,
+        inputs: Vec::new(),
+        output: None,
+        format: None,
+        quiet: false,
+        config: None,
+        log_level: None,
+        listen: None,
+        metrics_listen: None,
+        threads: None,
+        numeric: None,
+        verify: false,
+        detailed: false,
+        backpressure: None,
+        queue_capacity: None,
+        coalesce_window: None,
+        speed: None,
+        fee: None,
+        size: None,
+        latency_ms: None,
+        max_hops: None,
+        min_profit: None,
+        bench: BenchKind::FloydWarshall,
+        vertices: 300,
+        iterations: 3,
+        seed: 1,
+        help: false,
+    };
+
+    let mut args = args.iter().peekable();
+
+    if let Some(first) = args.peek() {
+        let command = match first.as_str() {
+            "run" => Some(Command::Run),
+            "serve" => Some(Command::Serve),
+            "replay" => Some(Command::Replay),
+            "check" => Some(Command::Check),
+            "bench" => Some(Command::Bench),
+            "backtest" => Some(Command::Backtest),
+            _ => None,
+        };
+        if let Some(command) = command {
+            cli.command = command;
+            args.next();
+        }
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-i" | "--input" => {
+                let mut found = false;
+                while let Some(value) = args.peek() {
+                    if value.starts_with('-') && value.as_str() != "-" {
+                        break;
+                    }
+                    cli.inputs.push(value.to_string());
+                    args.next();
+                    found = true;
+                }
+                if !found {
+                    return Err(format!("{} requires at least one file", arg));
+                }
+            }
+            "-o" | "--output" => cli.output = Some(option_value(arg, args.next())?),
+            "-f" | "--format" => {
+                cli.format = Some(parse_output_format(&option_value(arg, args.next())?)?)
+            }
+            "-q" | "--quiet" => cli.quiet = true,
+            "-c" | "--config" => cli.config = Some(option_value(arg, args.next())?),
+            "--log-level" => {
+                cli.log_level = Some(parse_log_level(&option_value(arg, args.next())?)?)
+            }
+            "--listen" => cli.listen = Some(option_value(arg, args.next())?),
+            "--metrics-listen" => cli.metrics_listen = Some(option_value(arg, args.next())?),
+            "--threads" => cli.threads = Some(number_value(arg, args.next())?),
+            "--numeric" => {
+                cli.numeric = Some(parse_numeric_mode(&option_value(arg, args.next())?)?)
+            }
+            "--detailed" => cli.detailed = true,
+            "--verify" => cli.verify = true,
+            "--backpressure" => {
+                cli.backpressure = Some(parse_backpressure(&option_value(arg, args.next())?)?)
+            }
+            "--queue-capacity" => cli.queue_capacity = Some(number_value(arg, args.next())?),
+            "--speed" => cli.speed = Some(parse_replay_speed(&option_value(arg, args.next())?)?),
+            "--coalesce-window" => cli.coalesce_window = Some(number_value(arg, args.next())?),
+            "--fee" => cli.fee = Some(number_value(arg, args.next())?),
+            "--size" => cli.size = Some(number_value(arg, args.next())?),
+            "--latency-ms" => cli.latency_ms = Some(number_value(arg, args.next())?),
+            "--max-hops" => cli.max_hops = Some(number_value(arg, args.next())?),
+            "--min-profit" => cli.min_profit = Some(number_value(arg, args.next())?),
+            "--bench" => cli.bench = parse_bench_kind(&option_value(arg, args.next())?)?,
+            "--vertices" => cli.vertices = number_value(arg, args.next())?,
+            "--iterations" => cli.iterations = number_value(arg, args.next())?,
+            "--seed" => cli.seed = number_value(arg, args.next())?,
+            "-h" | "--help" => cli.help = true,
+            _ => {
+                if arg.starts_with('-') && arg.as_str() != "-" {
+                    return Err(format!("Unknown option '{}'", arg));
+                }
+                // Bare arguments are treated as input files, e.g. `replay feed.txt`.
+                cli.inputs.push(arg.to_string());
+            }
+        }
+    }
+
+    if cli.command == Command::Replay && cli.inputs.is_empty() && !cli.help {
+        return Err("replay requires at least one recorded file".to_string());
+    }
+    if cli.command != Command::Replay && cli.speed.is_some() {
+        return Err("--speed only applies to replay".to_string());
+    }
+    if cli.command == Command::Backtest && cli.inputs.is_empty() && !cli.help {
+        return Err("backtest requires at least one recorded file".to_string());
+    }
+    if cli.command != Command::Backtest
+        && (cli.size.is_some()
+            || cli.latency_ms.is_some()
+            || cli.max_hops.is_some()
+            || cli.min_profit.is_some())
+    {
+        return Err(
+            "--size, --latency-ms, --max-hops and --min-profit only apply to backtest".to_string(),
+        );
+    }
+    if let Some(fee) = cli.fee {
+        if !(0.0..1.0).contains(&fee) {
+            return Err(format!(
+                "--fee must be at least 0 and less than 1, got {}",
+                fee
+            ));
+        }
+    }
+    validate_size("--size", cli.size)?;
+    validate_max_hops("--max-hops", cli.max_hops)?;
+    validate_min_profit("--min-profit", cli.min_profit)?;
+    return Ok(cli);
+}
+
+fn option_value(option: &str, value: Option<&String>) -> Result<String, String> {
+    match value {
+        Some(v) => Ok(v.to_string()),
+        None => Err(format!("{} requires a value", option)),
+    }
+}
+
+fn number_value<T: std::str::FromStr>(option: &str, value: Option<&String>) -> Result<T, String> {
+    let value = option_value(option, value)?;
+    return value
+        .parse()
+        .map_err(|_| format!("{} expects a number, got '{}'", option, value));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Cli, String> {
+        let args: Vec<String> = line.split_whitespace().map(|a| a.to_string()).collect();
+        return parse_args(&args);
+    }
+
+    #[test]
+    fn input_takes_every_file_up_to_the_next_option() {
+        let cli = parse("-i a.txt b.txt - --quiet c.txt").unwrap();
+        assert_eq!(cli.command, Command::Run);
+        assert_eq!(cli.inputs, ["a.txt", "b.txt", "-", "c.txt"]);
+        assert!(cli.quiet);
+
+        let cli = parse("run --input a.txt -o out.txt -f jsonl").unwrap();
+        assert_eq!(cli.inputs, ["a.txt"]);
+        assert_eq!(cli.output.as_deref(), Some("out.txt"));
+        assert_eq!(cli.format, Some(OutputFormat::Jsonl));
+
+        assert!(parse("").unwrap().inputs.is_empty());
+        assert_eq!(
+            parse("-i --quiet").err().as_deref(),
+            Some("-i requires at least one file")
+        );
+        assert_eq!(
+            parse("--input").err().as_deref(),
+            Some("--input requires at least one file")
+        );
+        assert_eq!(parse("-o").err().as_deref(), Some("-o requires a value"));
+        assert_eq!(
+            parse("--inputs a.txt").err().as_deref(),
+            Some("Unknown option '--inputs'")
+        );
+    }
+
+    #[test]
+    fn fee_must_be_a_fraction_below_one() {
+        assert_eq!(parse("--fee 0").unwrap().fee, Some(0.0));
+        assert_eq!(parse("--fee 0.0026").unwrap().fee, Some(0.0026));
+        assert_eq!(
+            parse("--fee 1").err().as_deref(),
+            Some("--fee must be at least 0 and less than 1, got 1")
+        );
+        assert_eq!(
+            parse("--fee -0.1").err().as_deref(),
+            Some("--fee must be at least 0 and less than 1, got -0.1")
+        );
+        assert_eq!(
+            parse("--fee a").err().as_deref(),
+            Some("--fee expects a number, got 'a'")
+        );
+    }
+
+    #[test]
+    fn options_for_one_command_are_rejected_on_the_others() {
+        assert_eq!(
+            parse("replay feed.txt --speed 10x").unwrap().speed,
+            Some(ReplaySpeed::Factor(10.0))
+        );
+        assert_eq!(
+            parse("run --speed 10x").err().as_deref(),
+            Some("--speed only applies to replay")
+        );
+        assert_eq!(
+            parse("replay").err().as_deref(),
+            Some("replay requires at least one recorded file")
+        );
+        assert!(parse("replay --help").unwrap().help);
+
+        let cli = parse("backtest feed.txt --size 2 --max-hops 3").unwrap();
+        assert_eq!(cli.command, Command::Backtest);
+        assert_eq!((cli.size, cli.max_hops), (Some(2.0), Some(3)));
+        for line in &[
+            "run --size 2",
+            "serve --latency-ms 5",
+            "replay feed.txt --max-hops 3",
+            "check --min-profit 0.01",
+        ] {
+            assert_eq!(
+                parse(line).err().as_deref(),
+                Some("--size, --latency-ms, --max-hops and --min-profit only apply to backtest"),
+                "{}",
+                line
+            );
+        }
+        assert_eq!(
+            parse("backtest").err().as_deref(),
+            Some("backtest requires at least one recorded file")
+        );
+    }
+}
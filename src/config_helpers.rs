@@ -0,0 +1,334 @@
+use crate::backtest_helpers::{validate_max_hops, validate_min_profit, validate_size};
+use crate::io_helpers::parse_output_format;
+use crate::log_helpers::parse_log_level;
+use crate::matrix_helpers::parse_numeric_mode;
+use crate::pipeline_helpers::parse_backpressure;
+use crate::replay_helpers::parse_replay_speed;
+use crate::symbol_helpers::parse_symbol_case;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::SocketAddr;
+
+/* Settings that can be loaded from a TOML file with --config.
+ * Every field is optional; anything given on the command line takes precedence.
+ *
+ *   log_level = "info"
+ *   numeric = "log-f64"
+ *
+ *   [output]
+ *   format = "jsonl"
+ *   file = "results.jsonl"
+ *   quiet = true
+ *   verify = true
+ *   detailed = false
+ *
+ *   [server]
+ *   listen = "127.0.0.1:7878"
+ *
+ *   [metrics]
+ *   listen = "127.0.0.1:9100"
+ *   stale_after_secs = 300
+ *
+ *   [engine]
+ *   threads = 4
+ *   quote_ttl_secs = 3600
+ *
+ *   [pipeline]
+ *   backpressure = "coalesce"
+ *   capacity = 1024
+ *   coalesce_window = 256
+ *
+ *   [replay]
+ *   speed = "10x"
+ *
+ *   [backtest]
+ *   size = 1.0
+ *   latency_ms = 250
+ *   max_hops = 4
+ *   min_profit = 0.001
+ *
+ *   [fees]
+ *   default = 0.001
+ *   [fees.exchanges]
+ *   KRAKEN = 0.0026
+ *
+ *   [transfer_costs]
+ *   default = 0.0
+ *   [transfer_costs.currencies]
+ *   BTC = 0.0005
+ *
+ *   [symbols]
+ *   case = "upper"
+ *   [symbols.currencies]
+ *   XBT = "BTC"
+ *   [symbols.exchanges]
+ *   COINBASE = "GDAX"
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: Option<String>,
+    pub numeric: Option<String>,
+    pub output: OutputConfig,
+    pub server: ServerConfig,
+    pub metrics: MetricsConfig,
+    pub engine: EngineConfig,
+    pub pipeline: PipelineConfig,
+    pub replay: ReplayConfig,
+    pub backtest: BacktestConfig,
+    pub fees: FeesConfig,
+    pub transfer_costs: TransferCostsConfig,
+    pub symbols: SymbolsConfig,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub format: Option<String>,
+    pub file: Option<String>,
+    pub quiet: Option<bool>,
+    pub verify: Option<bool>,
+    pub detailed: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub listen: Option<String>,
+    pub stale_after_secs: Option<i64>,
+}
+
+/* quote_ttl_secs drops trade edges whose quote is older than this, relative to the latest
+ * price update, from the rate tables. Unlike stale_after_secs it changes the results.
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    pub threads: Option<usize>,
+    pub quote_ttl_secs: Option<i64>,
+}
+
+/* The queue between reading the input and the engine, and what happens when it fills up.
+ * coalesce_window is the most price updates applied as one batch. 0 or 1 turns it off.
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineConfig {
+    pub backpressure: Option<String>,
+    pub capacity: Option<usize>,
+    pub coalesce_window: Option<usize>,
+}
+
+/* speed is how fast the replay command feeds a recorded file: max, realtime or e.g. 10x.
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplayConfig {
+    pub speed: Option<String>,
+}
+
+/* How the backtest command trades: the amount put through each cycle, how long after
+ * detecting a cycle it executes, the longest cycle in edges and the smallest profit to act on.
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BacktestConfig {
+    pub size: Option<f64>,
+    pub latency_ms: Option<u64>,
+    pub max_hops: Option<usize>,
+    pub min_profit: Option<f64>,
+}
+
+/* Trading fees as a fraction of the amount traded, e.g. 0.0026 for 0.26%.
+ * A trade edge's rate is multiplied by (1 - fee) when the rate tables are built.
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeesConfig {
+    pub default: Option<f64>,
+    pub exchanges: BTreeMap<String, f64>,
+}
+
+/* The cost of moving a currency between exchanges as a fraction of the amount moved.
+ * A transfer edge's rate is (1 - cost) instead of 1.
+ */
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransferCostsConfig {
+    pub default: Option<f64>,
+    pub currencies: BTreeMap<String, f64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SymbolsConfig {
+    pub case: Option<String>,
+    pub exchanges: BTreeMap<String, String>,
+    pub currencies: BTreeMap<String, String>,
+}
+
+pub fn load_config(path: &str) -> Result<Config, String> {
+    /* Reads, parses and validates the configuration file at the given path.
+     */
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Could not read config {}: {}", path, e))?;
+    let config: Config =
+        toml::from_str(&contents).map_err(|e| format!("Invalid config {}: {}", path, e))?;
+    validate_config(&config).map_err(|e| format!("Invalid config {}: {}", path, e))?;
+    return Ok(config);
+}
+
+pub fn validate_config(config: &Config) -> Result<(), String> {
+    /* Checks the values that TOML can't, so a bad setting is reported at startup with the
+     * name of the key rather than showing up later as odd results.
+     */
+    if let Some(level) = &config.log_level {
+        parse_log_level(level).map_err(|e| format!("log_level: {}", e))?;
+    }
+    if let Some(numeric) = &config.numeric {
+        parse_numeric_mode(numeric).map_err(|e| format!("numeric: {}", e))?;
+    }
+    if let Some(format) = &config.output.format {
+        parse_output_format(format).map_err(|e| format!("output.format: {}", e))?;
+    }
+    if let Some(policy) = &config.pipeline.backpressure {
+        parse_backpressure(policy).map_err(|e| format!("pipeline.backpressure: {}", e))?;
+    }
+    if let Some(speed) = &config.replay.speed {
+        parse_replay_speed(speed).map_err(|e| format!("replay.speed: {}", e))?;
+    }
+    if let Some(case) = &config.symbols.case {
+        parse_symbol_case(case).map_err(|e| format!("symbols.case: {}", e))?;
+    }
+    for (key, address) in [
+        ("server.listen", &config.server.listen),
+        ("metrics.listen", &config.metrics.listen),
+    ] {
+        if let Some(address) = address {
+            address.parse::<SocketAddr>().map_err(|_| {
+                format!(
+                    "{} must be an address like 127.0.0.1:7878, got '{}'",
+                    key, address
+                )
+            })?;
+        }
+    }
+    for (key, secs) in [
+        ("metrics.stale_after_secs", config.metrics.stale_after_secs),
+        ("engine.quote_ttl_secs", config.engine.quote_ttl_secs),
+    ] {
+        if let Some(secs) = secs {
+            if secs <= 0 {
+                return Err(format!("{} must be positive, got {}", key, secs));
+            }
+        }
+    }
+    if config.engine.threads == Some(0) {
+        return Err("engine.threads must be at least 1".to_string());
+    }
+    if config.pipeline.capacity == Some(0) {
+        return Err("pipeline.capacity must be at least 1".to_string());
+    }
+    validate_size("backtest.size", config.backtest.size)?;
+    validate_max_hops("backtest.max_hops", config.backtest.max_hops)?;
+    validate_min_profit("backtest.min_profit", config.backtest.min_profit)?;
+
+    validate_fraction("fees.default", config.fees.default)?;
+    for (exchange, fee) in &config.fees.exchanges {
+        validate_fraction(&format!("fees.exchanges.{}", exchange), Some(*fee))?;
+    }
+    validate_fraction("transfer_costs.default", config.transfer_costs.default)?;
+    for (currency, cost) in &config.transfer_costs.currencies {
+        validate_fraction(
+            &format!("transfer_costs.currencies.{}", currency),
+            Some(*cost),
+        )?;
+    }
+    return Ok(());
+}
+
+fn validate_fraction(key: &str, value: Option<f64>) -> Result<(), String> {
+    match value {
+        Some(v) if !(0.0..1.0).contains(&v) => Err(format!(
+            "{} must be at least 0 and less than 1, got {}",
+            key, v
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+        validate_config(&config)?;
+        return Ok(config);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(parse("log_level = \"info\"\n[engine]\nthreads = 4").is_ok());
+        for text in &[
+            "log_levle = \"info\"",
+            "[engine]\nthreds = 4",
+            "[fees]\ndefualt = 0.001",
+            "[caches]\nsize = 1",
+        ] {
+            assert!(parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn out_of_range_values_are_rejected_with_their_key() {
+        let cases: &[(&str, &str)] = &[
+            (
+                "[fees]\ndefault = 1.5",
+                "fees.default must be at least 0 and less than 1, got 1.5",
+            ),
+            (
+                "[fees.exchanges]\nKRAKEN = -0.1",
+                "fees.exchanges.KRAKEN must be at least 0 and less than 1, got -0.1",
+            ),
+            (
+                "[transfer_costs]\ndefault = 1.0",
+                "transfer_costs.default must be at least 0 and less than 1, got 1",
+            ),
+            ("[engine]\nthreads = 0", "engine.threads must be at least 1"),
+            (
+                "[engine]\nquote_ttl_secs = 0",
+                "engine.quote_ttl_secs must be positive, got 0",
+            ),
+            (
+                "[metrics]\nstale_after_secs = -5",
+                "metrics.stale_after_secs must be positive, got -5",
+            ),
+            (
+                "[pipeline]\ncapacity = 0",
+                "pipeline.capacity must be at least 1",
+            ),
+        ];
+        for (text, error) in cases {
+            assert_eq!(parse(text).err().as_deref(), Some(*error), "{}", text);
+        }
+        assert!(parse("[fees]\ndefault = 0.0\n[fees.exchanges]\nKRAKEN = 0.9999").is_ok());
+    }
+
+    #[test]
+    fn invalid_names_and_addresses_are_rejected() {
+        assert!(parse("log_level = \"loud\"")
+            .unwrap_err()
+            .starts_with("log_level: "));
+        assert!(parse("[server]\nlisten = \"localhost\"")
+            .unwrap_err()
+            .starts_with("server.listen must be an address"));
+    }
+}
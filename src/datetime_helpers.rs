@@ -7,11 +7,11 @@ extern crate chrono;
 
 use chrono::{DateTime, FixedOffset};
 
-pub fn get_datetime_from_string(input_string: &str) -> DateTime<FixedOffset> {
+pub fn get_datetime_from_string(input_string: &str) -> Result<DateTime<FixedOffset>, String> {
     /* Takes a string and returns it in the form DateTime<FixedOffset>
      */
     let dt = DateTime::parse_from_rfc3339(input_string);
-    return dt.unwrap();
+    return dt.map_err(|e| format!("Invalid timestamp '{}': {}", input_string, e));
 }
 
 pub fn is_more_recent(
@@ -26,14 +26,27 @@ pub fn is_more_recent(
 
     match diff_nano {
         Some(t) => {
-            if t > 0 {
-                return true;
-            } else {
-                return false;
-            }
+            return t > 0;
         }
         None => {
             return false;
         }
     }
 }
+
+pub fn format_age(age: chrono::Duration) -> String {
+    /* Formats a duration compactly for display, e.g. "30d 0h", "1h 5m" or "42s".
+     */
+    let seconds = age.num_seconds().max(0);
+    let (days, hours, minutes) = (seconds / 86400, seconds % 86400 / 3600, seconds % 3600 / 60);
+    if days > 0 {
+        return format!("{}d {}h", days, hours);
+    }
+    if hours > 0 {
+        return format!("{}h {}m", hours, minutes);
+    }
+    if minutes > 0 {
+        return format!("{}m {}s", minutes, seconds % 60);
+    }
+    return format!("{}s", seconds);
+}
@@ -0,0 +1,2127 @@
+use crate::config_helpers::Config;
+use crate::datetime_helpers::is_more_recent;
+use crate::export_helpers::{write_dot, write_rate_matrix, write_spreads};
+use crate::graph_helpers::{
+    add_vertex, check_consistency, get_edge_info, get_vertex_from_index,
+    process_edges_between_two_nodes, process_edges_same_currency, remove_pair, remove_vertex,
+    GraphIndex,
+};
+use crate::io_helpers::{
+    BatchResponse, ConsistencyReport, EdgeInfo, GraphStats, Hop, RateResponse, Response, Spread,
+    Venue, VenueReport,
+};
+use crate::log_helpers::{log_debug, log_warn};
+use crate::matrix_helpers::{DenseMatrix, LogRate, NumericMode};
+use crate::metrics_helpers::Metrics;
+use crate::modified_floyd_warshall_helpers::{
+    get_best_rates, get_path_from_request, make_best_rate_table, make_log_rate_table,
+    make_next_table, modified_floyd_warshall, verify_path, PathError, PARALLEL_THRESHOLD,
+};
+use crate::symbol_helpers::{canonical_currency, canonical_exchange};
+use crate::{Edge, EdgeKind, ExchangeRateRequest, Message, PairRemoval, PriceUpdate, Vertex};
+use chrono::{DateTime, FixedOffset};
+use petgraph::algo::connected_components;
+use petgraph::graph::node_index;
+use petgraph::visit::EdgeRef;
+use petgraph::Graph;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::time::Instant;
+
+/* The Engine owns the graph of exchange/currency vertices and the metadata of every edge.
+ * Price updates mutate it and Exchange Rate Requests are answered from it. Keeping this out of
+ * main lets the same logic be driven from stdin, files or a socket.
+ */
+pub const DEFAULT_STALE_AFTER_SECS: i64 = 300;
+
+/* Input goes to this namespace until a USE command picks another one.
+ */
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/* SPREADS lists the best quote for a pair across exchanges under this name.
+ */
+const ALL_EXCHANGES: &str = "*";
+
+/* An immutable copy of one version of a namespace along with its Floyd-Warshall result.
+ * A snapshot answers requests on its own, so readers on other threads can share it while
+ * the engine goes on applying updates.
+ */
+pub struct Snapshot {
+    namespace: String,
+    version: u64,
+    graph: Graph<String, Edge>,
+    halted: BTreeSet<String>,
+    last_update: Option<DateTime<FixedOffset>>,
+    verify: bool,
+    detailed: bool,
+    rate: DenseMatrix<f32>,
+    next: DenseMatrix<usize>,
+    edge_rates: Vec<Option<f32>>,
+}
+
+/* Costs applied on top of the raw quotes when the rate tables are built.
+ * Fees and transfer costs are fractions, e.g. 0.0026 for 0.26%.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Costs {
+    pub default_fee: f64,
+    pub fees: BTreeMap<String, f64>,
+    pub default_transfer_cost: f64,
+    pub transfer_costs: BTreeMap<String, f64>,
+    pub quote_ttl: Option<chrono::Duration>,
+}
+
+impl Costs {
+    fn fee(&self, exchange: &str) -> f64 {
+        return *self.fees.get(exchange).unwrap_or(&self.default_fee);
+    }
+
+    fn transfer_cost(&self, currency: &str) -> f64 {
+        return *self
+            .transfer_costs
+            .get(currency)
+            .unwrap_or(&self.default_transfer_cost);
+    }
+}
+
+/* A closed path through the graph, starting and ending at path[0], and the rate got by
+ * going round it once after fees and transfer costs. A rate above 1 is an arbitrage.
+ */
+#[derive(Clone, Debug)]
+pub struct Cycle {
+    pub path: Vec<Vertex>,
+    pub rate: f64,
+}
+
+/* Settings shared by every namespace.
+ */
+#[derive(Clone)]
+struct Options {
+    costs: Costs,
+    stale_after: chrono::Duration,
+    threads: usize,
+    numeric: NumericMode,
+    verify: bool,
+    detailed: bool,
+}
+
+/* One isolated book, e.g. a production feed next to a simulated one. Each namespace has its
+ * own graph, edge metadata, halted exchanges and cached rate tables, so updates to one never
+ * show up in answers from another.
+ */
+#[derive(Clone)]
+struct Namespace {
+    graph: Graph<String, Edge>,
+    index: GraphIndex,
+    version: u64,
+    snapshot: Option<Arc<Snapshot>>,
+    halted: BTreeSet<String>,
+    last_update: Option<DateTime<FixedOffset>>,
+    // When each pair was last removed, so a late quote from before then can't bring it back.
+    removed_pairs: BTreeMap<(String, String, String), DateTime<FixedOffset>>,
+    quote_times: QuoteTimes,
+}
+
+/* How many trade edges hold a quote from each time, along with how many of them were older
+ * than the last threshold asked about. The threshold only moves forward as updates arrive, so
+ * counting the stale quotes again only looks at the times it has passed since.
+ */
+#[derive(Clone, Default)]
+struct QuoteTimes {
+    counts: BTreeMap<DateTime<FixedOffset>, usize>,
+    threshold: Option<DateTime<FixedOffset>>,
+    stale: usize,
+}
+
+/* Re-reads the configuration and applies it to the engine. Set by main, which knows where
+ * the configuration came from and how to merge it with the command line.
+ */
+pub type Reloader = Arc<dyn Fn(&mut Engine) -> Result<(), String> + Send + Sync>;
+
+pub struct Engine {
+    namespaces: BTreeMap<String, Namespace>,
+    options: Options,
+    config: Config,
+    reloader: Option<Reloader>,
+    metrics: Arc<Metrics>,
+}
+
+impl Default for Engine {
+    fn default() -> Engine {
+        return Engine::new();
+    }
+}
+
+impl Engine {
+    pub fn new() -> Engine {
+        let mut namespaces = BTreeMap::new();
+        namespaces.insert(DEFAULT_NAMESPACE.to_string(), Namespace::new());
+        return Engine {
+            namespaces,
+            options: Options {
+                costs: Costs::default(),
+                stale_after: chrono::Duration::seconds(DEFAULT_STALE_AFTER_SECS),
+                threads: thread::available_parallelism().map_or(1, |n| n.get()),
+                numeric: NumericMode::F32,
+                verify: false,
+                detailed: false,
+            },
+            config: Config::default(),
+            reloader: None,
+            metrics: Arc::new(Metrics::default()),
+        };
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        return Arc::clone(&self.metrics);
+    }
+
+    pub fn set_threads(&mut self, threads: usize) {
+        /* Number of threads Floyd-Warshall may use on graphs of PARALLEL_THRESHOLD vertices
+         * or more. Smaller graphs always run on one thread.
+         */
+        self.options.threads = threads.max(1);
+    }
+
+    pub fn set_numeric_mode(&mut self, numeric: NumericMode) {
+        /* How rates are combined while Floyd-Warshall runs. Results are always reported as f32.
+         */
+        self.options.numeric = numeric;
+    }
+
+    pub fn set_verify(&mut self, verify: bool) {
+        /* Checks every returned path against the rate reported for it.
+         */
+        self.options.verify = verify;
+    }
+
+    pub fn set_detailed(&mut self, detailed: bool) {
+        /* Lists every hop of a best rate path with its kind, rate, cumulative rate and age.
+         */
+        self.options.detailed = detailed;
+    }
+
+    pub fn set_costs(&mut self, costs: Costs) {
+        /* Fees, transfer costs and the quote TTL applied when the rate tables are built.
+         */
+        self.options.costs = costs;
+        self.invalidate_tables();
+    }
+
+    pub fn set_config(&mut self, config: Config) {
+        /* The effective configuration, printed by CONFIG_SHOW.
+         */
+        self.config = config;
+    }
+
+    pub fn set_reloader(&mut self, reloader: Reloader) {
+        self.reloader = Some(reloader);
+    }
+
+    pub fn set_stale_after(&mut self, stale_after: chrono::Duration) {
+        /* Edges whose quote is older than this, relative to the latest price update,
+         * are counted as stale.
+         */
+        self.options.stale_after = stale_after;
+        self.update_stale_edges();
+    }
+
+    pub fn handle_message(
+        &mut self,
+        namespace: &str,
+        message: Message,
+    ) -> Result<Option<Response>, String> {
+        /* Applies a parsed line to the given namespace and returns the response to write,
+         * if any. The caller keeps track of which namespace its input is using.
+         */
+        match message {
+            Message::PriceUpdate(update) => {
+                self.apply_price_update(namespace, &update)?;
+                return Ok(None);
+            }
+            Message::ExchangeRateRequest(request) => {
+                let started = Instant::now();
+                let response = self.answer_request(namespace, &request);
+                self.metrics.request_duration.observe(started.elapsed());
+                Metrics::inc(&self.metrics.requests);
+                if response.is_err() {
+                    Metrics::inc(&self.metrics.requests_failed);
+                }
+                return Ok(Some(Response::BestRate(response?)));
+            }
+            Message::Batch(requests) => {
+                return Ok(Some(Response::Batch(
+                    self.answer_batch(namespace, &requests)?,
+                )))
+            }
+            Message::Use(name) => {
+                self.use_namespace(&name)?;
+                return Ok(Some(Response::Using(name)));
+            }
+            Message::ListNamespaces => {
+                return Ok(Some(Response::Namespaces(
+                    self.namespaces.keys().cloned().collect(),
+                )))
+            }
+            Message::Metrics => {
+                self.update_stale_edges();
+                return Ok(Some(Response::Metrics(self.metrics.render())));
+            }
+            Message::ListExchanges => {
+                return Ok(Some(Response::Exchanges(
+                    self.namespace(namespace)?.exchanges(),
+                )))
+            }
+            Message::ListCurrencies => {
+                return Ok(Some(Response::Currencies(
+                    self.namespace(namespace)?.currencies(),
+                )))
+            }
+            Message::ListEdges(exchange) => {
+                return Ok(Some(Response::Edges(
+                    self.edges(namespace, exchange.as_deref())?,
+                )));
+            }
+            Message::BestVenue(source, destination) => {
+                let report = self
+                    .snapshot(namespace)?
+                    .best_venues(&source, &destination)?;
+                return Ok(Some(Response::BestVenue(report)));
+            }
+            Message::Spreads(exchange) => {
+                let spreads = self.namespace(namespace)?.spreads(exchange.as_deref());
+                return Ok(Some(Response::Spreads(spreads)));
+            }
+            Message::ShowEdge(source, destination) => {
+                let edge = self
+                    .namespace(namespace)?
+                    .show_edge(&source, &destination)?;
+                return Ok(Some(Response::Edge(edge)));
+            }
+            Message::PairRemove(removal) => {
+                let removed = self.namespace_mut(namespace)?.remove_pair(&removal)?;
+                self.update_graph_metrics();
+                return Ok(Some(Response::Removed(removed)));
+            }
+            Message::VertexRemove(vertex) => {
+                let removed = self.namespace_mut(namespace)?.remove_vertex(&vertex)?;
+                self.update_graph_metrics();
+                return Ok(Some(Response::Removed(removed)));
+            }
+            Message::ExchangeHalt(exchange) => {
+                self.namespace_mut(namespace)?.set_halted(&exchange, true)?;
+                return Ok(Some(Response::ExchangeStatus(exchange, true)));
+            }
+            Message::ExchangeResume(exchange) => {
+                self.namespace_mut(namespace)?
+                    .set_halted(&exchange, false)?;
+                return Ok(Some(Response::ExchangeStatus(exchange, false)));
+            }
+            Message::ConfigShow => {
+                return Ok(Some(Response::Config(Box::new(self.config.clone()))))
+            }
+            Message::ConfigReload => {
+                self.reload_config()?;
+                return Ok(Some(Response::Reloaded));
+            }
+            Message::CheckConsistency => {
+                let ns = self.namespace(namespace)?;
+                return Ok(Some(Response::Consistency(
+                    ns.check_consistency(namespace, ns.snapshot.as_deref()),
+                )));
+            }
+            Message::Stats => {
+                return Ok(Some(Response::Stats(
+                    self.namespace(namespace)?.stats(namespace),
+                )))
+            }
+            Message::ExportDot(path) => {
+                let ns = self.namespace(namespace)?;
+                write_dot(&path, &ns.graph, ns.last_update, &ns.halted)?;
+                return Ok(Some(Response::Exported(path)));
+            }
+            Message::ExportMatrix(path) => {
+                let snapshot = self.snapshot(namespace)?;
+                write_rate_matrix(&path, &snapshot.graph, &snapshot.rate)?;
+                return Ok(Some(Response::Exported(path)));
+            }
+            Message::ExportSpreads(path) => {
+                write_spreads(&path, &self.namespace(namespace)?.spreads(None))?;
+                return Ok(Some(Response::Exported(path)));
+            }
+        }
+    }
+
+    pub fn use_namespace(&mut self, name: &str) -> Result<(), String> {
+        /* Creates the namespace if it doesn't exist yet. Names are kept to characters that
+         * can't be confused with the rest of a line.
+         */
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+        if !valid {
+            return Err(format!(
+                "Invalid namespace '{}'. Use letters, digits, '-', '_' or '.'",
+                name
+            ));
+        }
+        if !self.namespaces.contains_key(name) {
+            log_debug(&format!("Adding namespace {}", name));
+            self.namespaces.insert(name.to_string(), Namespace::new());
+        }
+        return Ok(());
+    }
+
+    fn versions(&self) -> BTreeMap<String, u64> {
+        return self
+            .namespaces
+            .iter()
+            .map(|(name, namespace)| (name.clone(), namespace.version))
+            .collect();
+    }
+
+    fn namespace(&self, name: &str) -> Result<&Namespace, String> {
+        return self
+            .namespaces
+            .get(name)
+            .ok_or_else(|| unknown_namespace(name));
+    }
+
+    fn namespace_mut(&mut self, name: &str) -> Result<&mut Namespace, String> {
+        return self
+            .namespaces
+            .get_mut(name)
+            .ok_or_else(|| unknown_namespace(name));
+    }
+
+    fn invalidate_tables(&mut self) {
+        /* Forces every namespace to rebuild its rate tables on the next request.
+         */
+        for namespace in self.namespaces.values_mut() {
+            namespace.version += 1;
+        }
+    }
+
+    pub fn reload_config(&mut self) -> Result<(), String> {
+        /* Re-reads the configuration file. If it fails to load or validate the error is
+         * returned and the engine carries on with the old configuration. Otherwise the cached
+         * tables are dropped so every rate is re-derived from the raw quotes on the edges.
+         */
+        let reloader = match &self.reloader {
+            Some(reloader) => Arc::clone(reloader),
+            None => return Err("No configuration file to reload.".to_string()),
+        };
+        reloader(self)?;
+        self.invalidate_tables();
+        return Ok(());
+    }
+
+    pub fn canonicalise_symbols(&mut self) {
+        /* Renames the vertices of every namespace under the current symbol aliases.
+         */
+        for namespace in self.namespaces.values_mut() {
+            namespace.canonicalise_symbols();
+        }
+        self.update_graph_metrics();
+    }
+
+    pub fn apply_price_update(
+        &mut self,
+        namespace: &str,
+        incoming_price_update: &PriceUpdate,
+    ) -> Result<(), String> {
+        let mut results =
+            self.apply_price_updates(namespace, std::slice::from_ref(incoming_price_update))?;
+        return results.remove(0);
+    }
+
+    pub fn apply_price_updates(
+        &mut self,
+        namespace: &str,
+        updates: &[PriceUpdate],
+    ) -> Result<Vec<Result<(), String>>, String> {
+        /* Applies a batch of price updates as one change to the graph. The version goes up
+         * once, so the rate tables are rebuilt at most once for the whole batch.
+         * Returns the result of each update in the same order.
+         */
+        let results = self.namespace_mut(namespace)?.apply_price_updates(updates);
+        let applied = results.iter().filter(|r| r.is_ok()).count();
+        Metrics::add(&self.metrics.price_updates, applied);
+        Metrics::inc(&self.metrics.update_batches);
+        self.update_graph_metrics();
+        return Ok(results);
+    }
+
+    pub fn answer_request(
+        &mut self,
+        namespace: &str,
+        rate_request: &ExchangeRateRequest,
+    ) -> Result<RateResponse, String> {
+        /* Nothing new to add for a request.
+         * Look up the best rate and the path taken to achieve it in the Floyd-Warshall result
+         * for the current graph, running it first if the graph has changed since the last one.
+         * A namespace given in the request itself wins over the one in use.
+         */
+        let name = rate_request.namespace.as_deref().unwrap_or(namespace);
+        return self.snapshot(name)?.answer(rate_request);
+    }
+
+    pub fn answer_batch(
+        &mut self,
+        namespace: &str,
+        requests: &[ExchangeRateRequest],
+    ) -> Result<BatchResponse, String> {
+        let metrics = Arc::clone(&self.metrics);
+        return answer_batch(namespace, requests, &metrics, |name| self.snapshot(name));
+    }
+
+    pub fn snapshot(&mut self, name: &str) -> Result<Arc<Snapshot>, String> {
+        /* The snapshot for the current version of a namespace, rebuilding it if needed.
+         */
+        let namespace = self
+            .namespaces
+            .get_mut(name)
+            .ok_or_else(|| unknown_namespace(name))?;
+        return namespace.snapshot(name, &self.options, &self.metrics);
+    }
+
+    fn snapshot_source(&self, name: &str) -> Result<(Namespace, Options), String> {
+        /* A copy of everything needed to build a snapshot of the namespace elsewhere.
+         */
+        let namespace = self.namespace(name)?;
+        namespace.ready()?;
+        return Ok((namespace.clone(), self.options.clone()));
+    }
+
+    pub fn best_cycle(
+        &self,
+        namespace: &str,
+        source: &Vertex,
+        destination: &Vertex,
+        max_hops: usize,
+    ) -> Result<Option<Cycle>, String> {
+        /* The most profitable cycle of at most max_hops edges that starts with the edge from
+         * source to destination, at the rates the rate tables would use.
+         */
+        return Ok(self.namespace(namespace)?.best_cycle(
+            source,
+            destination,
+            &self.options.costs,
+            max_hops,
+        ));
+    }
+
+    pub fn edges(&self, namespace: &str, exchange: Option<&str>) -> Result<Vec<EdgeInfo>, String> {
+        return Ok(self.namespace(namespace)?.edges(exchange));
+    }
+
+    pub fn cycle_rate(&self, namespace: &str, path: &[Vertex]) -> Result<Option<f64>, String> {
+        /* The current rate round a cycle, or None if one of its edges is gone or left out.
+         */
+        return Ok(self
+            .namespace(namespace)?
+            .cycle_rate(path, &self.options.costs));
+    }
+
+    fn update_graph_metrics(&mut self) {
+        /* Refreshes the graph size and stale edge gauges after a graph has changed.
+         * The gauges add up every namespace.
+         */
+        let namespaces = self.namespaces.values();
+        Metrics::set(
+            &self.metrics.vertices,
+            namespaces.clone().map(|ns| ns.graph.node_count()).sum(),
+        );
+        Metrics::set(
+            &self.metrics.edges,
+            namespaces.map(|ns| ns.graph.edge_count()).sum(),
+        );
+        self.update_stale_edges();
+    }
+
+    fn update_stale_edges(&mut self) {
+        /* Each namespace keeps count of its quotes by time, so this doesn't look at the edges.
+         */
+        let stale_after = self.options.stale_after;
+        let namespaces = self.namespaces.values_mut();
+        Metrics::set(
+            &self.metrics.stale_edges,
+            namespaces.map(|ns| ns.stale_edges(stale_after)).sum(),
+        );
+    }
+}
+
+/* An engine shared between threads with one writer and many readers. Updates and commands go
+ * through the writer lock, which is only ever held for as long as it takes to apply them.
+ * Requests are answered from the latest published snapshot of their namespace, which is an
+ * Arc swapped in under a short write lock, so readers never hold the engine while they look
+ * up a rate.
+ *
+ * The version of every namespace is copied out each time the writer lock is released, so
+ * readers can tell whether the published snapshot is current without taking the lock.
+ * When a reader finds the snapshot is behind the graph, it copies the namespace under the
+ * writer lock and runs Floyd-Warshall on the copy without it. Other readers arriving while
+ * that is running answer from the previous snapshot instead of waiting for it. The version
+ * in every answer says which graph it came from.
+ */
+pub struct SharedEngine {
+    writer: Mutex<Engine>,
+    versions: RwLock<BTreeMap<String, u64>>,
+    published: RwLock<BTreeMap<String, Arc<Snapshot>>>,
+    rebuilding: Mutex<BTreeSet<String>>,
+    metrics: Arc<Metrics>,
+}
+
+/* The writer lock on a SharedEngine. Dropping it publishes the namespace versions.
+ */
+pub struct EngineWriter<'a> {
+    engine: MutexGuard<'a, Engine>,
+    versions: &'a RwLock<BTreeMap<String, u64>>,
+}
+
+impl Deref for EngineWriter<'_> {
+    type Target = Engine;
+
+    fn deref(&self) -> &Engine {
+        return &self.engine;
+    }
+}
+
+impl DerefMut for EngineWriter<'_> {
+    fn deref_mut(&mut self) -> &mut Engine {
+        return &mut self.engine;
+    }
+}
+
+impl Drop for EngineWriter<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut versions) = self.versions.write() {
+            *versions = self.engine.versions();
+        }
+    }
+}
+
+impl SharedEngine {
+    pub fn new(engine: Engine) -> SharedEngine {
+        return SharedEngine {
+            metrics: engine.metrics(),
+            versions: RwLock::new(engine.versions()),
+            writer: Mutex::new(engine),
+            published: RwLock::new(BTreeMap::new()),
+            rebuilding: Mutex::new(BTreeSet::new()),
+        };
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        return Arc::clone(&self.metrics);
+    }
+
+    pub fn writer(&self) -> Result<EngineWriter<'_>, String> {
+        let engine = self
+            .writer
+            .lock()
+            .map_err(|_| "Engine is unavailable".to_string())?;
+        return Ok(EngineWriter {
+            engine,
+            versions: &self.versions,
+        });
+    }
+
+    pub fn handle_message(
+        &self,
+        namespace: &str,
+        message: Message,
+    ) -> Result<Option<Response>, String> {
+        /* Same as Engine::handle_message, except that requests are answered from snapshots and
+         * exports are refused, since the file names come from the clients.
+         */
+        match message {
+            Message::ExchangeRateRequest(request) => {
+                let started = Instant::now();
+                let name = request.namespace.as_deref().unwrap_or(namespace);
+                let response = self.snapshot(name).and_then(|s| s.answer(&request));
+                self.metrics.request_duration.observe(started.elapsed());
+                Metrics::inc(&self.metrics.requests);
+                if response.is_err() {
+                    Metrics::inc(&self.metrics.requests_failed);
+                }
+                return Ok(Some(Response::BestRate(response?)));
+            }
+            Message::Batch(requests) => {
+                let batch = answer_batch(namespace, &requests, &self.metrics, |name| {
+                    self.snapshot(name)
+                })?;
+                return Ok(Some(Response::Batch(batch)));
+            }
+            Message::ExportDot(_) | Message::ExportMatrix(_) | Message::ExportSpreads(_) => {
+                // Any client could otherwise overwrite any file the server can write.
+                return Err(
+                    "Exports write files on the server and aren't available over serve."
+                        .to_string(),
+                );
+            }
+            Message::BestVenue(source, destination) => {
+                let report = self
+                    .snapshot(namespace)?
+                    .best_venues(&source, &destination)?;
+                return Ok(Some(Response::BestVenue(report)));
+            }
+            Message::CheckConsistency => {
+                // The engine's own snapshot isn't used here, so check the published one.
+                let published = self.published(namespace);
+                let engine = self.writer()?;
+                return Ok(Some(Response::Consistency(
+                    engine
+                        .namespace(namespace)?
+                        .check_consistency(namespace, published.as_deref()),
+                )));
+            }
+            message => return self.writer()?.handle_message(namespace, message),
+        }
+    }
+
+    fn published(&self, name: &str) -> Option<Arc<Snapshot>> {
+        return match self.published.read() {
+            Ok(published) => published.get(name).cloned(),
+            Err(_) => None,
+        };
+    }
+
+    fn snapshot(&self, name: &str) -> Result<Arc<Snapshot>, String> {
+        let published = self.published(name);
+        let version = match self.versions.read() {
+            Ok(versions) => versions.get(name).copied(),
+            Err(_) => return Err("Engine is unavailable".to_string()),
+        }
+        .ok_or_else(|| unknown_namespace(name))?;
+
+        {
+            let mut rebuilding = self
+                .rebuilding
+                .lock()
+                .map_err(|_| "Engine is unavailable".to_string())?;
+            if let Some(snapshot) = published {
+                if snapshot.version == version || rebuilding.contains(name) {
+                    return Ok(snapshot);
+                }
+            }
+            rebuilding.insert(name.to_string());
+        }
+
+        let source = self
+            .writer()
+            .and_then(|engine| engine.snapshot_source(name));
+        let (namespace, options) = match source {
+            Ok(source) => source,
+            Err(e) => {
+                if let Ok(mut rebuilding) = self.rebuilding.lock() {
+                    rebuilding.remove(name);
+                }
+                return Err(e);
+            }
+        };
+        let snapshot = Arc::new(Snapshot::build(name, namespace, &options, &self.metrics));
+        if let Ok(mut published) = self.published.write() {
+            let newer = published
+                .get(name)
+                .is_none_or(|current| current.version < snapshot.version);
+            if newer {
+                published.insert(name.to_string(), Arc::clone(&snapshot));
+            }
+        }
+        if let Ok(mut rebuilding) = self.rebuilding.lock() {
+            rebuilding.remove(name);
+        }
+        return Ok(snapshot);
+    }
+}
+
+fn answer_batch<F>(
+    namespace: &str,
+    requests: &[ExchangeRateRequest],
+    metrics: &Metrics,
+    mut snapshot: F,
+) -> Result<BatchResponse, String>
+where
+    F: FnMut(&str) -> Result<Arc<Snapshot>, String>,
+{
+    /* Answers every request in a batch from the same snapshot, so the answers are consistent
+     * with each other. A request that can't be answered gets an error without failing the
+     * rest of the batch. Requests naming another namespace are answered from one snapshot of
+     * that namespace in the same way.
+     */
+    let own = match snapshot(namespace) {
+        Ok(own) => own,
+        Err(e) => {
+            Metrics::add(&metrics.requests, requests.len());
+            Metrics::add(&metrics.requests_failed, requests.len());
+            return Err(e);
+        }
+    };
+    let mut snapshots = BTreeMap::new();
+    snapshots.insert(namespace.to_string(), Ok(Arc::clone(&own)));
+    let mut results = Vec::new();
+    for request in requests {
+        let started = Instant::now();
+        let name = request.namespace.as_deref().unwrap_or(namespace);
+        let response = snapshots
+            .entry(name.to_string())
+            .or_insert_with(|| snapshot(name))
+            .clone()
+            .and_then(|s| s.answer(request));
+        metrics.request_duration.observe(started.elapsed());
+        Metrics::inc(&metrics.requests);
+        if response.is_err() {
+            Metrics::inc(&metrics.requests_failed);
+        }
+        results.push(response);
+    }
+    return Ok(BatchResponse {
+        version: own.version,
+        results,
+    });
+}
+
+fn pair_key(exchange: &str, a: &str, b: &str) -> (String, String, String) {
+    /* A pair on an exchange regardless of which way round it was given.
+     */
+    let (a, b) = if a <= b { (a, b) } else { (b, a) };
+    return (exchange.to_string(), a.to_string(), b.to_string());
+}
+
+fn unknown_namespace(name: &str) -> String {
+    return format!(
+        "Namespace {} does not exist. Create it with USE {}",
+        name, name
+    );
+}
+
+impl Namespace {
+    fn new() -> Namespace {
+        return Namespace {
+            graph: Graph::<String, Edge>::new(),
+            index: GraphIndex::default(),
+            version: 0,
+            snapshot: None,
+            halted: BTreeSet::new(),
+            last_update: None,
+            removed_pairs: BTreeMap::new(),
+            quote_times: QuoteTimes::default(),
+        };
+    }
+
+    fn canonicalise_symbols(&mut self) {
+        /* Rebuilds the graph under the current symbol aliases by replaying the trade quotes it
+         * holds. Vertices whose names change are renamed, and ones that now share a name are
+         * merged, keeping the most recent quote for each pair.
+         */
+        let mut quotes = BTreeMap::new();
+        for e in self.graph.edge_indices() {
+            if let Some(info) = get_edge_info(e, &self.graph) {
+                if info.kind == EdgeKind::Trade {
+                    let key = (
+                        info.source.exchange,
+                        info.source.currency,
+                        info.destination.currency,
+                    );
+                    quotes.insert(key, (info.rate, info.timestamp));
+                }
+            }
+        }
+
+        let mut updates = Vec::new();
+        for ((exchange, source, destination), (rate, timestamp)) in &quotes {
+            let reverse = (exchange.clone(), destination.clone(), source.clone());
+            let backward = match quotes.get(&reverse) {
+                Some((backward, _)) => *backward,
+                None => {
+                    log_warn(&format!(
+                        "Dropping the one-way quote {} {} -> {} while renaming symbols",
+                        exchange, source, destination
+                    ));
+                    continue;
+                }
+            };
+            // Each pair is replayed once, from its first direction.
+            if source > destination {
+                continue;
+            }
+            let update = PriceUpdate {
+                timestamp: *timestamp,
+                exchange: canonical_exchange(exchange),
+                source_currency: canonical_currency(source),
+                destination_currency: canonical_currency(destination),
+                forward_factor: *rate,
+                backward_factor: backward,
+            };
+            if update.source_currency == update.destination_currency {
+                log_warn(&format!(
+                    "Dropping the quote {} {} <-> {} as both currencies are now {}",
+                    exchange, source, destination, update.source_currency
+                ));
+                continue;
+            }
+            updates.push(update);
+        }
+        updates.sort_by_key(|u| u.timestamp);
+
+        self.graph = Graph::<String, Edge>::new();
+        self.index = GraphIndex::default();
+        self.quote_times = QuoteTimes::default();
+        for result in self.apply_price_updates(&updates) {
+            if let Err(e) = result {
+                log_warn(&e);
+            }
+        }
+        self.halted = self
+            .halted
+            .iter()
+            .map(|exchange| canonical_exchange(exchange))
+            .collect();
+        let removed_pairs = std::mem::take(&mut self.removed_pairs);
+        for ((exchange, a, b), timestamp) in removed_pairs {
+            self.record_removal(
+                pair_key(
+                    &canonical_exchange(&exchange),
+                    &canonical_currency(&a),
+                    &canonical_currency(&b),
+                ),
+                timestamp,
+            );
+        }
+        self.version += 1;
+    }
+
+    fn apply_price_updates(&mut self, updates: &[PriceUpdate]) -> Vec<Result<(), String>> {
+        /* Applies the updates in order as a single change, so the version only goes up once.
+         */
+        let results: Vec<Result<(), String>> = updates
+            .iter()
+            .map(|update| self.apply_price_update(update))
+            .collect();
+        if results.iter().any(|r| r.is_ok()) {
+            self.version += 1;
+        }
+        return results;
+    }
+
+    fn apply_price_update(&mut self, incoming_price_update: &PriceUpdate) -> Result<(), String> {
+        let key = pair_key(
+            &incoming_price_update.exchange,
+            &incoming_price_update.source_currency,
+            &incoming_price_update.destination_currency,
+        );
+        if let Some(removed) = self.removed_pairs.get(&key) {
+            if !is_more_recent(incoming_price_update.timestamp, *removed) {
+                return Err(format!(
+                    "Ignoring a quote from before the pair was removed at {}",
+                    removed.to_rfc3339()
+                ));
+            }
+            self.removed_pairs.remove(&key);
+        }
+
+        /* ======================= Adding Vertices ===============================
+         * Check if source and/or destination vertices already exist in our graph
+         * and only add one or both do not.
+         */
+        let vertex_source = Vertex {
+            exchange: incoming_price_update.exchange.clone(),
+            currency: incoming_price_update.source_currency.clone(),
+        };
+        let vertex_destination = Vertex {
+            exchange: incoming_price_update.exchange.clone(),
+            currency: incoming_price_update.destination_currency.clone(),
+        };
+
+        let was_new = (
+            self.index.get(&vertex_source).is_none(),
+            self.index.get(&vertex_destination).is_none(),
+        );
+        let source_node_index = add_vertex(&vertex_source, &mut self.graph, &mut self.index);
+        let dest_node_index = add_vertex(&vertex_destination, &mut self.graph, &mut self.index);
+
+        // ====================== Adding edges ======================
+        /* The following handles edge creation between vertexes that share
+         * the same currency but different exchanges.
+         *
+         *  Connect vertex_destination to the other exchanges that support that currency,
+         *  including an edge to itself with a weight of 1.0. Those edges are made when the
+         *  vertex is added, so a vertex already in the graph has them.
+         */
+        if was_new.1 {
+            process_edges_same_currency(
+                dest_node_index,
+                &vertex_destination,
+                incoming_price_update,
+                &mut self.graph,
+                &mut self.index,
+            );
+        }
+
+        /* Do the same for vertex_source's currency.
+         */
+        if was_new.0 {
+            process_edges_same_currency(
+                source_node_index,
+                &vertex_source,
+                incoming_price_update,
+                &mut self.graph,
+                &mut self.index,
+            );
+        }
+
+        /* The following adds edges as specified in the incoming price update.
+         * It only adds edges if they are either found not to exist or if the
+         * incoming price update is more recent than the existing rate.
+         * This is done through the function process_edges_between_two_nodes.
+         */
+        let pair = [
+            (source_node_index, dest_node_index),
+            (dest_node_index, source_node_index),
+        ];
+        for (u, v) in pair {
+            self.count_quote(u, v, false);
+        }
+        process_edges_between_two_nodes(
+            source_node_index,
+            dest_node_index,
+            incoming_price_update,
+            &mut self.graph,
+            &mut self.index,
+        );
+        for (u, v) in pair {
+            self.count_quote(u, v, true);
+        }
+
+        let newer = self
+            .last_update
+            .is_none_or(|t| is_more_recent(incoming_price_update.timestamp, t));
+        if newer {
+            self.last_update = Some(incoming_price_update.timestamp);
+        }
+        return Ok(());
+    }
+
+    fn remove_pair(&mut self, removal: &PairRemoval) -> Result<usize, String> {
+        /* Deletes the trade edges in both directions between two currencies on an exchange.
+         * The vertices and their transfer edges stay in the graph.
+         */
+        let source = Vertex {
+            exchange: removal.exchange.clone(),
+            currency: removal.source_currency.clone(),
+        };
+        let destination = Vertex {
+            exchange: removal.exchange.clone(),
+            currency: removal.destination_currency.clone(),
+        };
+        let u = self.index.get(&source);
+        let v = self.index.get(&destination);
+        let (u, v) = match (u, v) {
+            (Some(u), Some(v)) => (u, v),
+            _ => return Err("Either Source or Destination does not exist yet.".to_string()),
+        };
+        if self.index.find_edge(u, v).is_none() && self.index.find_edge(v, u).is_none() {
+            return Err("There is no edge between those vertices.".to_string());
+        }
+
+        for (a, b) in [(u, v), (v, u)] {
+            self.count_quote(a, b, false);
+        }
+        let removed = remove_pair(u, v, removal.timestamp, &mut self.graph, &mut self.index);
+        for (a, b) in [(u, v), (v, u)] {
+            self.count_quote(a, b, true);
+        }
+        self.record_removal(
+            pair_key(
+                &removal.exchange,
+                &removal.source_currency,
+                &removal.destination_currency,
+            ),
+            removal.timestamp,
+        );
+        self.version += 1;
+        return Ok(removed);
+    }
+
+    fn record_removal(&mut self, key: (String, String, String), timestamp: DateTime<FixedOffset>) {
+        let removed = self.removed_pairs.entry(key).or_insert(timestamp);
+        if is_more_recent(timestamp, *removed) {
+            *removed = timestamp;
+        }
+    }
+
+    fn remove_vertex(&mut self, vertex: &Vertex) -> Result<usize, String> {
+        /* Deletes a vertex and every edge into or out of it.
+         */
+        let removed = remove_vertex(vertex, &mut self.graph, &mut self.index)
+            .ok_or_else(|| "Vertex does not exist.".to_string())?;
+        // The indices of the other vertices may have moved, so the quotes are counted again.
+        self.recount_quotes();
+        self.version += 1;
+        return Ok(removed);
+    }
+
+    fn set_halted(&mut self, exchange: &str, halted: bool) -> Result<(), String> {
+        /* Halting an exchange takes its vertices out of the rate tables so requests are routed
+         * around it. Its edges and quotes are kept and come back into use when it resumes.
+         */
+        if !self.exchanges().iter().any(|e| e == exchange) {
+            return Err(format!("Exchange {} does not exist yet.", exchange));
+        }
+        let changed = if halted {
+            self.halted.insert(exchange.to_string())
+        } else {
+            self.halted.remove(exchange)
+        };
+        if changed {
+            self.version += 1;
+        }
+        return Ok(());
+    }
+
+    fn edge_rates(&self, costs: &Costs) -> Vec<Option<f32>> {
+        /* Works out the rate each edge contributes to the rate tables from its raw quote.
+         * Trade edges are reduced by the exchange's fee and transfer edges by the currency's
+         * transfer cost. Edges touching a halted exchange, and trade edges older than the
+         * quote TTL, get None and are left out.
+         */
+        let vertices = self.vertex_table();
+        return self
+            .graph
+            .raw_edges()
+            .iter()
+            .map(|edge| {
+                let source = vertices[edge.source().index()].as_ref()?;
+                let destination = vertices[edge.target().index()].as_ref()?;
+                self.edge_rate(source, destination, &edge.weight, costs)
+            })
+            .collect();
+    }
+
+    fn edge_rate(
+        &self,
+        source: &Vertex,
+        destination: &Vertex,
+        edge: &Edge,
+        costs: &Costs,
+    ) -> Option<f32> {
+        if self.halted.contains(&source.exchange) || self.halted.contains(&destination.exchange) {
+            return None;
+        }
+        let factor = match edge.kind {
+            EdgeKind::Trade => {
+                if let (Some(ttl), Some(now)) = (costs.quote_ttl, self.last_update) {
+                    if now - edge.timestamp > ttl {
+                        return None;
+                    }
+                }
+                1.0 - costs.fee(&source.exchange)
+            }
+            EdgeKind::Transfer if source == destination => 1.0,
+            EdgeKind::Transfer => 1.0 - costs.transfer_cost(&source.currency),
+        };
+        return Some((edge.rate as f64 * factor) as f32);
+    }
+
+    fn vertex_table(&self) -> Vec<Option<Vertex>> {
+        return (0..self.graph.node_count())
+            .map(|i| get_vertex_from_index(i, &self.graph))
+            .collect();
+    }
+
+    fn best_cycle(
+        &self,
+        source: &Vertex,
+        destination: &Vertex,
+        costs: &Costs,
+        max_hops: usize,
+    ) -> Option<Cycle> {
+        /* A depth-first search from destination back to source over simple paths, so the
+         * cost grows with the number of edges near the pair rather than the whole graph.
+         */
+        let u = self.index.get(source)?;
+        let v = self.index.get(destination)?;
+        let first = self.index.find_edge(u, v)?;
+        let rate = self.edge_rate(source, destination, &self.graph[first], costs)? as f64;
+        let vertices = self.vertex_table();
+        let mut best = None;
+        self.extend_cycle(&vertices, costs, max_hops, &mut vec![u, v], rate, &mut best);
+        return best.map(|(rate, path): (f64, Vec<usize>)| Cycle {
+            path: path.iter().filter_map(|i| vertices[*i].clone()).collect(),
+            rate,
+        });
+    }
+
+    fn extend_cycle(
+        &self,
+        vertices: &[Option<Vertex>],
+        costs: &Costs,
+        max_hops: usize,
+        path: &mut Vec<usize>,
+        rate: f64,
+        best: &mut Option<(f64, Vec<usize>)>,
+    ) {
+        let last = path[path.len() - 1];
+        for edge in self.graph.edges(node_index(last)) {
+            let next = edge.target().index();
+            if next == last {
+                continue;
+            }
+            let (source, destination) = match (&vertices[last], &vertices[next]) {
+                (Some(source), Some(destination)) => (source, destination),
+                _ => continue,
+            };
+            let edge_rate = match self.edge_rate(source, destination, edge.weight(), costs) {
+                Some(r) => r as f64,
+                None => continue,
+            };
+            if next == path[0] {
+                if best.as_ref().is_none_or(|(b, _)| rate * edge_rate > *b) {
+                    *best = Some((rate * edge_rate, path.clone()));
+                }
+                continue;
+            }
+            // Leave room for the edge back to the start.
+            if path.len() >= max_hops || path.contains(&next) {
+                continue;
+            }
+            path.push(next);
+            self.extend_cycle(vertices, costs, max_hops, path, rate * edge_rate, best);
+            path.pop();
+        }
+    }
+
+    fn cycle_rate(&self, path: &[Vertex], costs: &Costs) -> Option<f64> {
+        let mut rate = 1.0;
+        for (i, source) in path.iter().enumerate() {
+            let destination = &path[(i + 1) % path.len()];
+            let e = self
+                .index
+                .find_edge(self.index.get(source)?, self.index.get(destination)?)?;
+            rate *= self.edge_rate(source, destination, &self.graph[e], costs)? as f64;
+        }
+        return Some(rate);
+    }
+
+    fn ready(&self) -> Result<(), String> {
+        if self.graph.node_count() == 0 {
+            return Err("No price updates have been received yet.".to_string());
+        }
+        return Ok(());
+    }
+
+    fn snapshot(
+        &mut self,
+        name: &str,
+        options: &Options,
+        metrics: &Metrics,
+    ) -> Result<Arc<Snapshot>, String> {
+        /* Returns the snapshot for the current version of the graph.
+         * It is only rebuilt after something has changed the graph.
+         */
+        self.ready()?;
+        if let Some(snapshot) = &self.snapshot {
+            if snapshot.version == self.version {
+                return Ok(Arc::clone(snapshot));
+            }
+        }
+        let snapshot = Arc::new(Snapshot::build(name, self.clone(), options, metrics));
+        self.snapshot = Some(Arc::clone(&snapshot));
+        return Ok(snapshot);
+    }
+
+    fn stale_edges(&mut self, stale_after: chrono::Duration) -> usize {
+        /* Trade edges whose quote is older than stale_after, relative to the latest price
+         * update. Transfer edges carry no quote, so they are never stale.
+         */
+        return match self.last_update {
+            Some(now) => self.quote_times.older_than(now - stale_after),
+            None => 0,
+        };
+    }
+
+    fn count_quote(&mut self, u: usize, v: usize, add: bool) {
+        /* Adds the quote on the trade edge from u to v, if there is one, to quote_times, or
+         * takes it away.
+         */
+        let edge = match self.index.find_edge(u, v) {
+            Some(e) if self.graph[e].kind == EdgeKind::Trade => &self.graph[e],
+            _ => return,
+        };
+        match add {
+            true => self.quote_times.add(edge.timestamp),
+            false => self.quote_times.remove(edge.timestamp),
+        }
+    }
+
+    fn recount_quotes(&mut self) {
+        self.quote_times = QuoteTimes::default();
+        for edge in self.graph.raw_edges() {
+            if edge.weight.kind == EdgeKind::Trade {
+                self.quote_times.add(edge.weight.timestamp);
+            }
+        }
+    }
+
+    fn vertices(&self) -> Vec<Vertex> {
+        return (0..self.graph.node_count())
+            .filter_map(|i| get_vertex_from_index(i, &self.graph))
+            .collect();
+    }
+
+    fn exchanges(&self) -> Vec<String> {
+        /* Every exchange that has appeared in a price update, sorted by name.
+         */
+        let exchanges: BTreeSet<String> = self.vertices().into_iter().map(|v| v.exchange).collect();
+        return exchanges.into_iter().collect();
+    }
+
+    fn currencies(&self) -> Vec<String> {
+        /* Every currency that has appeared in a price update, sorted by name.
+         */
+        let currencies: BTreeSet<String> =
+            self.vertices().into_iter().map(|v| v.currency).collect();
+        return currencies.into_iter().collect();
+    }
+
+    fn edges(&self, exchange: Option<&str>) -> Vec<EdgeInfo> {
+        /* Lists the edges of the graph. If an exchange is given, only edges that start or end
+         * on that exchange are included.
+         */
+        return self
+            .graph
+            .edge_indices()
+            .filter_map(|e| get_edge_info(e, &self.graph))
+            .filter(|edge| match exchange {
+                Some(ex) => edge.source.exchange == ex || edge.destination.exchange == ex,
+                None => true,
+            })
+            .collect();
+    }
+
+    fn spreads(&self, exchange: Option<&str>) -> Vec<Spread> {
+        /* The latest quote for every pair on every exchange, or on the given exchange, sorted by
+         * pair and then exchange so the exchanges quoting a pair can be compared.
+         * Each pair is a trade edge and the edge back, and is taken from the edge that goes
+         * towards the higher vertex index.
+         * Without an exchange, each pair also gets a row for the best quote across exchanges,
+         * which sorts ahead of the exchanges it was taken from.
+         */
+        let mut quotes: Vec<PairQuote> = self
+            .graph
+            .edge_indices()
+            .filter_map(|e| {
+                let (u, v) = self.graph.edge_endpoints(e)?;
+                if u.index() >= v.index() || self.graph[e].kind != EdgeKind::Trade {
+                    return None;
+                }
+                let back = self.index.find_edge(v.index(), u.index())?;
+                let edge = get_edge_info(e, &self.graph)?;
+                if exchange.is_some_and(|ex| ex != edge.source.exchange) {
+                    return None;
+                }
+                let forward = edge.rate as f64;
+                let backward = self.graph[back].rate as f64;
+                let (base, quote, forward, backward) = if forward >= backward {
+                    (edge.source, edge.destination, forward, backward)
+                } else {
+                    (edge.destination, edge.source, backward, forward)
+                };
+                return Some(PairQuote {
+                    exchange: base.exchange,
+                    base: base.currency,
+                    quote: quote.currency,
+                    forward,
+                    backward,
+                    timestamp: edge.timestamp,
+                });
+            })
+            .collect();
+        quotes.sort_by(|a, b| {
+            (&a.base, &a.quote, &a.exchange).cmp(&(&b.base, &b.quote, &b.exchange))
+        });
+        if exchange.is_none() {
+            let best = best_across_exchanges(&quotes);
+            quotes.extend(best);
+            quotes.sort_by(|a, b| {
+                (&a.base, &a.quote, &a.exchange).cmp(&(&b.base, &b.quote, &b.exchange))
+            });
+        }
+        let spreads: Vec<Spread> = quotes.iter().map(PairQuote::spread).collect();
+        return spreads;
+    }
+
+    fn show_edge(&self, source: &Vertex, destination: &Vertex) -> Result<EdgeInfo, String> {
+        let u = self.index.get(source);
+        let v = self.index.get(destination);
+        let edge = match (u, v) {
+            (Some(u), Some(v)) => self.index.find_edge(u, v),
+            _ => return Err("Either Source or Destination does not exist yet.".to_string()),
+        };
+        return edge
+            .and_then(|e| get_edge_info(e, &self.graph))
+            .ok_or_else(|| "There is no edge between those vertices.".to_string());
+    }
+
+    fn check_consistency(&self, namespace: &str, snapshot: Option<&Snapshot>) -> ConsistencyReport {
+        /* Checks the graph against its index and edge metadata, and the given rate tables,
+         * if they are for the current version, against the graph they were built from.
+         */
+        let mut problems = check_consistency(&self.graph, &self.index);
+        if let Some(snapshot) = snapshot {
+            if snapshot.version == self.version {
+                let n = self.graph.node_count();
+                if snapshot.rate.rows != n || snapshot.next.rows != n {
+                    problems.push(format!(
+                        "The rate tables have {} rows for {} vertices",
+                        snapshot.rate.rows, n
+                    ));
+                }
+                if snapshot.edge_rates.len() != self.graph.edge_count() {
+                    problems.push(format!(
+                        "The rate tables have {} edge rates for {} edges",
+                        snapshot.edge_rates.len(),
+                        self.graph.edge_count()
+                    ));
+                }
+            }
+        }
+        return ConsistencyReport {
+            namespace: namespace.to_string(),
+            vertices: self.graph.node_count(),
+            edges: self.graph.edge_count(),
+            problems,
+        };
+    }
+
+    fn stats(&self, namespace: &str) -> GraphStats {
+        return GraphStats {
+            namespace: namespace.to_string(),
+            vertices: self.graph.node_count(),
+            edges: self.graph.edge_count(),
+            components: connected_components(&self.graph),
+            last_update: self.last_update,
+            version: self.version,
+            halted: self.halted.iter().cloned().collect(),
+        };
+    }
+}
+
+impl QuoteTimes {
+    fn add(&mut self, time: DateTime<FixedOffset>) {
+        *self.counts.entry(time).or_insert(0) += 1;
+        if self.threshold.is_some_and(|threshold| time < threshold) {
+            self.stale += 1;
+        }
+    }
+
+    fn remove(&mut self, time: DateTime<FixedOffset>) {
+        if let Some(count) = self.counts.get_mut(&time) {
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&time);
+            }
+            if self.threshold.is_some_and(|threshold| time < threshold) {
+                self.stale -= 1;
+            }
+        }
+    }
+
+    fn older_than(&mut self, threshold: DateTime<FixedOffset>) -> usize {
+        let counts = &self.counts;
+        self.stale = match self.threshold {
+            Some(previous) if previous <= threshold => {
+                self.stale
+                    + counts
+                        .range(previous..threshold)
+                        .map(|(_, n)| n)
+                        .sum::<usize>()
+            }
+            _ => counts.range(..threshold).map(|(_, n)| n).sum(),
+        };
+        self.threshold = Some(threshold);
+        return self.stale;
+    }
+}
+
+impl Snapshot {
+    fn build(name: &str, namespace: Namespace, options: &Options, metrics: &Metrics) -> Snapshot {
+        /* Runs Floyd-Warshall over a copy of the namespace. This is the expensive part, so it
+         * doesn't need the engine at all.
+         */
+        let started = Instant::now();
+
+        /* Initialise the next table as defined in the challenge brief.
+         * The best rate table is built for whichever numeric mode is in use.
+         */
+        let edge_rates = namespace.edge_rates(&options.costs);
+        let next = make_next_table(&namespace.graph, &edge_rates);
+
+        //============== MODIFIED FLOYD-WARSHALL ======================
+        /* Run the algorithm to get the best rates and next tables for our graph
+         * and update both lookup tables with the results.
+         */
+        let threads = if namespace.graph.node_count() >= PARALLEL_THRESHOLD {
+            options.threads
+        } else {
+            1
+        };
+        let (rate, next) = match options.numeric {
+            NumericMode::F32 => {
+                let rate = make_best_rate_table(&namespace.graph, &edge_rates);
+                modified_floyd_warshall(&rate, &next, threads)
+            }
+            NumericMode::LogF64 => {
+                let rate = make_log_rate_table(&namespace.graph, &edge_rates);
+                let (rate, next) = modified_floyd_warshall(&rate, &next, threads);
+                (rate.map(LogRate::to_rate), next)
+            }
+        };
+        metrics.floyd_warshall_duration.observe(started.elapsed());
+        return Snapshot {
+            namespace: name.to_string(),
+            version: namespace.version,
+            graph: namespace.graph,
+            halted: namespace.halted,
+            last_update: namespace.last_update,
+            verify: options.verify,
+            detailed: options.detailed,
+            rate,
+            next,
+            edge_rates,
+        };
+    }
+
+    pub fn answer(&self, rate_request: &ExchangeRateRequest) -> Result<RateResponse, String> {
+        for exchange in [
+            &rate_request.source_exchange,
+            &rate_request.destination_exchange,
+        ] {
+            if self.halted.contains(exchange) {
+                return Err(format!("Exchange {} is halted.", exchange));
+            }
+        }
+
+        /* ========== Process Request ==============
+         *  get_best_rates looks up the best possible rate between the desired source and
+         *  destination, and get_path_from_request returns the path required to achieve it.
+         */
+        let best_rate = match get_best_rates(rate_request.clone(), &self.rate, &self.graph) {
+            Some(r) => r,
+            None => return Err("Either Source or Destination does not exist yet.".to_string()),
+        };
+
+        let indices = match get_path_from_request(rate_request, &self.next, &self.graph) {
+            Ok(p) => Some(p),
+            Err(PathError::NoPath) => None,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        /* With --verify, multiply the rates along the path again to make sure they give the
+         * rate being reported.
+         */
+        let check = match &indices {
+            Some(p) if self.verify => {
+                Some(verify_path(p, &self.graph, &self.edge_rates, best_rate))
+            }
+            _ => None,
+        };
+        let hops = match &indices {
+            Some(p) if self.detailed => Some(self.hops(p)),
+            _ => None,
+        };
+        let path = indices.map(|p| {
+            p.iter()
+                .filter_map(|i| get_vertex_from_index(*i, &self.graph))
+                .collect()
+        });
+
+        return Ok(RateResponse {
+            request: rate_request.clone(),
+            rate: best_rate,
+            path,
+            check,
+            hops,
+            version: self.version,
+            namespace: self.namespace.clone(),
+        });
+    }
+
+    pub fn best_venues(
+        &self,
+        source_currency: &str,
+        destination_currency: &str,
+    ) -> Result<VenueReport, String> {
+        /* Answers a request from every exchange quoting the source currency to every exchange
+         * quoting the destination currency, and ranks them by rate. Halted exchanges are left
+         * out. The direct rate is the trade edge as the rate tables see it, after fees.
+         */
+        let vertices: Vec<(usize, Vertex)> = (0..self.graph.node_count())
+            .filter_map(|i| Some((i, get_vertex_from_index(i, &self.graph)?)))
+            .filter(|(_, v)| !self.halted.contains(&v.exchange))
+            .collect();
+        let sources: Vec<&(usize, Vertex)> = vertices
+            .iter()
+            .filter(|(_, v)| v.currency == source_currency)
+            .collect();
+        let destinations: Vec<&(usize, Vertex)> = vertices
+            .iter()
+            .filter(|(_, v)| v.currency == destination_currency)
+            .collect();
+        for (currency, found) in [
+            (source_currency, &sources),
+            (destination_currency, &destinations),
+        ] {
+            if found.is_empty() {
+                return Err(format!("No exchange is quoting {} yet.", currency));
+            }
+        }
+
+        let mut venues = Vec::new();
+        for (u, source) in &sources {
+            for (v, destination) in &destinations {
+                if u == v {
+                    continue;
+                }
+                let response = self.answer(&ExchangeRateRequest {
+                    source_exchange: source.exchange.clone(),
+                    source_currency: source.currency.clone(),
+                    destination_exchange: destination.exchange.clone(),
+                    destination_currency: destination.currency.clone(),
+                    namespace: None,
+                });
+                // A venue that can't be answered is listed as unreachable rather than failing
+                // the whole report.
+                let (rate, path) = match response {
+                    Ok(response) => (response.rate, response.path),
+                    Err(e) => {
+                        log_debug(&format!(
+                            "BEST_VENUE {} to {}: {}",
+                            source.exchange, destination.exchange, e
+                        ));
+                        (0.0, None)
+                    }
+                };
+                // Trade edges stay on one exchange, so any edge between different exchanges is
+                // a transfer.
+                let direct = match source.exchange == destination.exchange {
+                    true => self
+                        .graph
+                        .find_edge(node_index(*u), node_index(*v))
+                        .and_then(|e| self.edge_rates.get(e.index()).copied().flatten()),
+                    false => None,
+                };
+                venues.push(Venue {
+                    source_exchange: source.exchange.clone(),
+                    destination_exchange: destination.exchange.clone(),
+                    rate,
+                    direct,
+                    path,
+                });
+            }
+        }
+        // Equal rates go to the venue with a direct trade, then to the shorter path.
+        let hops = |venue: &Venue| venue.path.as_ref().map_or(usize::MAX, |p| p.len());
+        venues.sort_by(|a, b| {
+            b.rate
+                .partial_cmp(&a.rate)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| {
+                    b.direct
+                        .partial_cmp(&a.direct)
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                })
+                .then_with(|| hops(a).cmp(&hops(b)))
+        });
+
+        return Ok(VenueReport {
+            source_currency: source_currency.to_string(),
+            destination_currency: destination_currency.to_string(),
+            venues,
+            version: self.version,
+            namespace: self.namespace.clone(),
+        });
+    }
+
+    fn hops(&self, path: &[usize]) -> Vec<Hop> {
+        /* Looks up the edge behind every hop of a path, for --detailed output.
+         * The rate shown is the one applied in the rate table, after fees and costs.
+         */
+        let mut cumulative_rate: f64 = 1.0;
+        let mut hops = Vec::new();
+        for hop in path.windows(2) {
+            let edge = self
+                .graph
+                .find_edge(node_index(hop[0]), node_index(hop[1]))
+                .and_then(|e| {
+                    let mut edge = get_edge_info(e, &self.graph)?;
+                    edge.rate = self.edge_rates.get(e.index()).copied().flatten()?;
+                    Some(edge)
+                });
+            if let Some(edge) = edge {
+                cumulative_rate *= edge.rate as f64;
+                let age = match self.last_update {
+                    Some(now) => now - edge.timestamp,
+                    None => chrono::Duration::zero(),
+                };
+                hops.push(Hop {
+                    edge,
+                    cumulative_rate: cumulative_rate as f32,
+                    age,
+                });
+            }
+        }
+        return hops;
+    }
+}
+
+/* The latest rates for a pair on one exchange, forward from base to quote and backward from
+ * quote to base, before they are turned into a Spread.
+ */
+struct PairQuote {
+    exchange: String,
+    base: String,
+    quote: String,
+    forward: f64,
+    backward: f64,
+    timestamp: DateTime<FixedOffset>,
+}
+
+impl PairQuote {
+    fn spread(&self) -> Spread {
+        let bid = self.forward;
+        let ask = 1.0 / self.backward;
+        let mid = (bid + ask) / 2.0;
+        return Spread {
+            exchange: self.exchange.clone(),
+            base: self.base.clone(),
+            quote: self.quote.clone(),
+            bid: bid as f32,
+            ask: ask as f32,
+            mid: mid as f32,
+            spread_bps: ((ask - bid) / mid * 10_000.0) as f32,
+            round_trip: (self.forward * self.backward) as f32,
+            timestamp: self.timestamp,
+        };
+    }
+}
+
+fn best_across_exchanges(quotes: &[PairQuote]) -> Vec<PairQuote> {
+    /* One quote per pair under the exchange ALL_EXCHANGES, with the best rate each way of any
+     * exchange quoting it, so the highest bid and the lowest ask. Selling at one and buying back
+     * at the other gives a round trip above 1 and a negative spread when the exchanges cross.
+     * An exchange that quotes the pair the other way round from the first quote for it is turned
+     * over before comparing.
+     */
+    let mut best: BTreeMap<(String, String), PairQuote> = BTreeMap::new();
+    for quote in quotes {
+        let reversed = (quote.quote.clone(), quote.base.clone());
+        let (key, forward, backward) = if best.contains_key(&reversed) {
+            (reversed, quote.backward, quote.forward)
+        } else {
+            (
+                (quote.base.clone(), quote.quote.clone()),
+                quote.forward,
+                quote.backward,
+            )
+        };
+        let row = best
+            .entry(key)
+            .or_insert_with_key(|(base, counter)| PairQuote {
+                exchange: ALL_EXCHANGES.to_string(),
+                base: base.clone(),
+                quote: counter.clone(),
+                forward,
+                backward,
+                timestamp: quote.timestamp,
+            });
+        row.forward = row.forward.max(forward);
+        row.backward = row.backward.max(backward);
+        if is_more_recent(quote.timestamp, row.timestamp) {
+            row.timestamp = quote.timestamp;
+        }
+    }
+    return best.into_values().collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io_helpers::parse_line;
+    use std::sync::atomic::Ordering;
+
+    fn send(engine: &mut Engine, line: &str) -> Result<Option<Response>, String> {
+        let message = parse_line(line)?.expect("a message");
+        return engine.handle_message(DEFAULT_NAMESPACE, message);
+    }
+
+    fn rate(engine: &mut Engine) -> f32 {
+        match send(engine, "EXCHANGE_RATE_REQUEST KRAKEN BTC KRAKEN USD") {
+            Ok(Some(Response::BestRate(response))) => return response.rate,
+            _ => panic!("expected a rate"),
+        }
+    }
+
+    #[test]
+    fn a_quote_from_before_a_removal_does_not_bring_the_pair_back() {
+        let mut engine = Engine::new();
+        send(
+            &mut engine,
+            "2017-11-01T09:42:23+00:00 KRAKEN BTC USD 1000.0 0.0009",
+        )
+        .unwrap();
+        send(
+            &mut engine,
+            "PAIR_REMOVE 2017-11-01T09:43:00+00:00 KRAKEN USD BTC",
+        )
+        .unwrap();
+        assert_eq!(rate(&mut engine), 0.0);
+
+        assert!(send(
+            &mut engine,
+            "2017-11-01T09:42:50+00:00 KRAKEN BTC USD 1001.0 0.0009"
+        )
+        .is_err());
+        assert!(send(
+            &mut engine,
+            "2017-11-01T09:43:00+00:00 KRAKEN BTC USD 1002.0 0.0009"
+        )
+        .is_err());
+        assert_eq!(rate(&mut engine), 0.0);
+
+        send(
+            &mut engine,
+            "2017-11-01T09:43:01+00:00 KRAKEN BTC USD 1003.0 0.0009",
+        )
+        .unwrap();
+        assert_eq!(rate(&mut engine), 1003.0);
+    }
+
+    #[test]
+    fn shared_engine_answers_from_a_current_snapshot_without_the_writer_lock() {
+        let shared = Arc::new(SharedEngine::new(Engine::new()));
+        let request = "EXCHANGE_RATE_REQUEST KRAKEN BTC KRAKEN USD";
+        for line in &[
+            "2017-11-01T09:42:23+00:00 KRAKEN BTC USD 1000.0 0.0009",
+            request,
+        ] {
+            let message = parse_line(line).unwrap().unwrap();
+            shared.handle_message(DEFAULT_NAMESPACE, message).unwrap();
+        }
+
+        let _writer = shared.writer().unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let reader = Arc::clone(&shared);
+        thread::spawn(move || {
+            let message = parse_line(request).unwrap().unwrap();
+            let _ = sender.send(reader.handle_message(DEFAULT_NAMESPACE, message));
+        });
+        let response = receiver.recv_timeout(std::time::Duration::from_secs(5));
+        match response {
+            Ok(Ok(Some(Response::BestRate(response)))) => assert_eq!(response.rate, 1000.0),
+            _ => panic!("expected a rate while the writer lock was held"),
+        }
+    }
+
+    #[test]
+    fn shared_engine_checks_the_published_snapshot() {
+        let shared = SharedEngine::new(Engine::new());
+        for line in &[
+            "2017-11-01T09:42:23+00:00 KRAKEN BTC USD 1000.0 0.0009",
+            "EXCHANGE_RATE_REQUEST KRAKEN BTC KRAKEN USD",
+        ] {
+            let message = parse_line(line).unwrap().unwrap();
+            shared.handle_message(DEFAULT_NAMESPACE, message).unwrap();
+        }
+        let mut published = shared.published.write().unwrap();
+        let tables = published.get_mut(DEFAULT_NAMESPACE).unwrap();
+        Arc::get_mut(tables).unwrap().edge_rates.pop();
+        drop(published);
+
+        match shared.handle_message(DEFAULT_NAMESPACE, Message::CheckConsistency) {
+            Ok(Some(Response::Consistency(report))) => assert_eq!(report.problems.len(), 1),
+            _ => panic!("expected a consistency report"),
+        }
+    }
+
+    #[test]
+    fn best_venues_lists_a_venue_it_cannot_answer_as_unreachable() {
+        let shared = SharedEngine::new(Engine::new());
+        for line in &[
+            "2017-11-01T09:42:23+00:00 KRAKEN BTC USD 1000.0 0.0009",
+            "2017-11-01T09:42:24+00:00 GDAX BTC USD 1001.0 0.0008",
+            "EXCHANGE_RATE_REQUEST KRAKEN BTC KRAKEN USD",
+        ] {
+            let message = parse_line(line).unwrap().unwrap();
+            shared.handle_message(DEFAULT_NAMESPACE, message).unwrap();
+        }
+        let mut published = shared.published.write().unwrap();
+        let snapshot = Arc::get_mut(published.get_mut(DEFAULT_NAMESPACE).unwrap()).unwrap();
+        let find = |currency: &str| {
+            (0..snapshot.graph.node_count())
+                .find(|i| {
+                    get_vertex_from_index(*i, &snapshot.graph)
+                        .is_some_and(|v| v.exchange == "KRAKEN" && v.currency == currency)
+                })
+                .unwrap()
+        };
+        let (btc, usd) = (find("BTC"), find("USD"));
+        // A next table that goes round in circles, so the path can't be read out of it.
+        snapshot.next.set((btc, usd), btc);
+
+        let report = snapshot.best_venues("BTC", "USD").unwrap();
+        assert_eq!(report.venues.len(), 4);
+        let broken: Vec<&Venue> = report
+            .venues
+            .iter()
+            .filter(|v| v.source_exchange == "KRAKEN" && v.destination_exchange == "KRAKEN")
+            .collect();
+        assert_eq!(broken.len(), 1);
+        assert_eq!(broken[0].rate, 0.0);
+        assert!(broken[0].path.is_none());
+        assert!(report.venues[0].rate > 0.0);
+    }
+
+    #[test]
+    fn failed_requests_are_counted() {
+        let mut engine = Engine::new();
+        let metrics = engine.metrics();
+        send(
+            &mut engine,
+            "2017-11-01T09:42:23+00:00 KRAKEN BTC USD 1000.0 0.0009",
+        )
+        .unwrap();
+        assert!(send(&mut engine, "EXCHANGE_RATE_REQUEST KRAKEN BTC GDAX USD").is_err());
+        let requests =
+            ["KRAKEN BTC KRAKEN USD", "KRAKEN BTC GDAX USD"].map(|pair| {
+                match parse_line(&format!("EXCHANGE_RATE_REQUEST {}", pair)) {
+                    Ok(Some(Message::ExchangeRateRequest(request))) => request,
+                    _ => panic!("expected a request"),
+                }
+            });
+        let batch = engine.handle_message(DEFAULT_NAMESPACE, Message::Batch(requests.to_vec()));
+        assert!(batch.is_ok());
+        assert_eq!(metrics.requests.load(Ordering::Relaxed), 3);
+        assert_eq!(metrics.requests_failed.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn stale_edges_counts_old_trade_quotes_after_each_update() {
+        let mut engine = Engine::new();
+        let metrics = engine.metrics();
+        let stale = || metrics.stale_edges.load(Ordering::Relaxed);
+        send(
+            &mut engine,
+            "2017-11-01T09:00:00+00:00 KRAKEN BTC USD 1000.0 0.0009",
+        )
+        .unwrap();
+        send(
+            &mut engine,
+            "2017-11-01T09:00:00+00:00 GDAX BTC USD 1001.0 0.0009",
+        )
+        .unwrap();
+        assert_eq!(stale(), 0);
+
+        // The transfer edges between KRAKEN and GDAX are as old, but hold no quote.
+        send(
+            &mut engine,
+            "2017-11-01T09:06:40+00:00 KRAKEN ETH BTC 0.05 19.0",
+        )
+        .unwrap();
+        assert_eq!(stale(), 4);
+        assert!(metrics.edges.load(Ordering::Relaxed) > 6);
+        engine.set_stale_after(chrono::Duration::seconds(600));
+        assert_eq!(stale(), 0);
+        engine.set_stale_after(chrono::Duration::seconds(300));
+        assert_eq!(stale(), 4);
+
+        send(
+            &mut engine,
+            "2017-11-01T09:06:41+00:00 GDAX USD BTC 0.0009 1001.0",
+        )
+        .unwrap();
+        assert_eq!(stale(), 2);
+        send(
+            &mut engine,
+            "PAIR_REMOVE 2017-11-01T09:06:42+00:00 KRAKEN BTC USD",
+        )
+        .unwrap();
+        assert_eq!(stale(), 0);
+    }
+
+    fn spreads(engine: &mut Engine, line: &str) -> Vec<Spread> {
+        match send(engine, line) {
+            Ok(Some(Response::Spreads(spreads))) => return spreads,
+            _ => panic!("expected spreads"),
+        }
+    }
+
+    fn assert_close(actual: f32, expected: f64) {
+        assert!(
+            ((actual as f64 - expected) / expected).abs() < 1e-5,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn spreads_add_the_best_quote_across_exchanges_for_each_pair() {
+        let mut engine = Engine::new();
+        for line in &[
+            "2017-11-01T09:00:00+00:00 KRAKEN BTC USD 1000.0 0.0009",
+            "2017-11-01T09:00:01+00:00 GDAX BTC USD 1001.0 0.00099",
+            "2017-11-01T09:00:02+00:00 KRAKEN EUR USD 1.1 0.95",
+            // Quoted as USD/EUR, since the rate back is the larger.
+            "2017-11-01T09:00:03+00:00 GDAX EUR USD 0.98 1.03",
+        ] {
+            send(&mut engine, line).unwrap();
+        }
+        let rows = spreads(&mut engine, "SPREADS");
+        let names: Vec<String> = rows
+            .iter()
+            .map(|s| format!("{} {}/{}", s.exchange, s.base, s.quote))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "* BTC/USD",
+                "GDAX BTC/USD",
+                "KRAKEN BTC/USD",
+                "* EUR/USD",
+                "KRAKEN EUR/USD",
+                "GDAX USD/EUR"
+            ]
+        );
+
+        let kraken = &rows[2];
+        assert_close(kraken.bid, 1000.0);
+        assert_close(kraken.ask, 1.0 / 0.0009);
+        assert_close(kraken.mid, (1000.0 + 1.0 / 0.0009) / 2.0);
+        assert_close(kraken.round_trip, 0.9);
+
+        // GDAX has the best bid and the best ask.
+        let btc = &rows[0];
+        let (bid, ask) = (1001.0, 1.0 / 0.00099);
+        assert_close(btc.bid, bid);
+        assert_close(btc.ask, ask);
+        assert_close(btc.mid, (bid + ask) / 2.0);
+        assert_close(btc.spread_bps, (ask - bid) / ((bid + ask) / 2.0) * 10_000.0);
+        assert_close(btc.round_trip, bid / ask);
+        assert_eq!(btc.timestamp, rows[1].timestamp);
+
+        // KRAKEN bids more for EUR than GDAX asks, so the exchanges cross.
+        let eur = &rows[3];
+        let (bid, ask) = (1.1, 1.0 / 1.03);
+        assert_close(eur.bid, bid);
+        assert_close(eur.ask, ask);
+        assert_close(eur.spread_bps, (ask - bid) / ((bid + ask) / 2.0) * 10_000.0);
+        assert!(eur.spread_bps < 0.0);
+        assert_close(eur.round_trip, bid / ask);
+        assert_eq!(eur.timestamp, rows[5].timestamp);
+
+        let kraken_only = spreads(&mut engine, "SPREADS KRAKEN");
+        assert_eq!(kraken_only.len(), 2);
+        assert!(kraken_only.iter().all(|s| s.exchange == "KRAKEN"));
+    }
+
+    #[test]
+    fn a_halted_exchange_is_bypassed_until_it_resumes() {
+        let mut engine = Engine::new();
+        for line in &[
+            "2017-11-01T09:00:00+00:00 KRAKEN BTC USD 1000.0 0.0009",
+            "2017-11-01T09:00:01+00:00 GDAX BTC USD 1001.0 0.0008",
+        ] {
+            send(&mut engine, line).unwrap();
+        }
+        let gdax_quote = |engine: &mut Engine| match send(engine, "SHOW_EDGE GDAX BTC GDAX USD") {
+            Ok(Some(Response::Edge(edge))) => return (edge.rate, edge.timestamp),
+            _ => panic!("expected an edge"),
+        };
+        let quote = gdax_quote(&mut engine);
+        assert_eq!(rate(&mut engine), 1001.0);
+
+        send(&mut engine, "EXCHANGE_HALT GDAX").unwrap();
+        assert_eq!(rate(&mut engine), 1000.0);
+        assert!(matches!(
+            send(&mut engine, "EXCHANGE_RATE_REQUEST GDAX BTC KRAKEN USD"),
+            Err(e) if e == "Exchange GDAX is halted."
+        ));
+        assert!(matches!(
+            send(&mut engine, "EXCHANGE_RATE_REQUEST KRAKEN BTC GDAX USD"),
+            Err(e) if e == "Exchange GDAX is halted."
+        ));
+        assert_eq!(gdax_quote(&mut engine), quote);
+
+        send(&mut engine, "EXCHANGE_RESUME GDAX").unwrap();
+        assert_eq!(gdax_quote(&mut engine), quote);
+        assert_eq!(rate(&mut engine), 1001.0);
+        match send(&mut engine, "EXCHANGE_RATE_REQUEST GDAX BTC GDAX USD") {
+            Ok(Some(Response::BestRate(response))) => assert_eq!(response.rate, 1001.0),
+            _ => panic!("expected a rate"),
+        }
+    }
+
+    #[test]
+    fn namespaces_are_isolated_and_must_exist() {
+        let mut engine = Engine::new();
+        let mut send_to = |namespace: &str, line: &str| {
+            let message = parse_line(line).unwrap().expect("a message");
+            return engine.handle_message(namespace, message);
+        };
+        send_to(
+            DEFAULT_NAMESPACE,
+            "2017-11-01T09:42:23+00:00 KRAKEN BTC USD 1000.0 0.0009",
+        )
+        .unwrap();
+        send_to(DEFAULT_NAMESPACE, "USE sim").unwrap();
+        send_to(
+            "sim",
+            "2017-11-01T09:42:24+00:00 KRAKEN BTC USD 2000.0 0.0004",
+        )
+        .unwrap();
+        send_to(
+            "sim",
+            "2017-11-01T09:42:25+00:00 GDAX BTC USD 2001.0 0.0004",
+        )
+        .unwrap();
+        send_to("sim", "EXCHANGE_HALT KRAKEN").unwrap();
+
+        let mut rate_in = |namespace: &str, request: &str| match send_to(namespace, request) {
+            Ok(Some(Response::BestRate(response))) => return (response.rate, response.namespace),
+            _ => panic!("expected a rate"),
+        };
+        let request = "EXCHANGE_RATE_REQUEST KRAKEN BTC KRAKEN USD";
+        assert_eq!(
+            rate_in(DEFAULT_NAMESPACE, request),
+            (1000.0, DEFAULT_NAMESPACE.to_string())
+        );
+        assert_eq!(
+            rate_in(
+                DEFAULT_NAMESPACE,
+                "EXCHANGE_RATE_REQUEST GDAX BTC GDAX USD sim"
+            ),
+            (2001.0, "sim".to_string())
+        );
+        assert!(send_to("sim", request).is_err());
+        assert!(send_to(DEFAULT_NAMESPACE, "EXCHANGE_RATE_REQUEST GDAX BTC GDAX USD").is_err());
+
+        let missing = Some("Namespace live does not exist. Create it with USE live".to_string());
+        let request = "EXCHANGE_RATE_REQUEST KRAKEN BTC KRAKEN USD live";
+        assert!(send_to(DEFAULT_NAMESPACE, request).err() == missing);
+        assert!(send_to("live", "STATS").err() == missing);
+        match send_to(DEFAULT_NAMESPACE, "LIST_NAMESPACES") {
+            Ok(Some(Response::Namespaces(names))) => assert_eq!(names, ["default", "sim"]),
+            _ => panic!("expected the namespaces"),
+        }
+    }
+
+    #[test]
+    fn pair_remove_needs_two_different_currencies() {
+        assert!(parse_line("PAIR_REMOVE 2017-11-01T09:43:00+00:00 KRAKEN BTC BTC").is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use crate::datetime_helpers::format_age;
+use crate::graph_helpers::{get_edge_info, get_vertex_from_index};
+use crate::io_helpers::{csv_field, spread_csv, Spread};
+use crate::matrix_helpers::DenseMatrix;
+use crate::{Edge, EdgeKind, Vertex};
+use chrono::{DateTime, FixedOffset};
+use petgraph::Graph;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+
+pub fn write_dot(
+    path: &str,
+    graph: &Graph<String, Edge>,
+    now: Option<DateTime<FixedOffset>>,
+    halted: &BTreeSet<String>,
+) -> Result<(), String> {
+    /* Writes the graph in Graphviz DOT format. Vertices are labelled with their exchange and
+     * currency and edges with their rate and the age of the quote relative to the most recent
+     * price update. Trade edges are drawn solid and transfer edges dashed and grey.
+     * Vertices on halted exchanges are drawn dashed and red.
+     * Edges from a vertex to itself are left out since they always have a rate of 1.
+     */
+    let mut out = create(path)?;
+    return write_dot_to(&mut out, graph, now, halted)
+        .map_err(|e| format!("Error writing {}: {}", path, e));
+}
+
+fn write_dot_to(
+    out: &mut BufWriter<File>,
+    graph: &Graph<String, Edge>,
+    now: Option<DateTime<FixedOffset>>,
+    halted: &BTreeSet<String>,
+) -> io::Result<()> {
+    writeln!(out, "digraph rates {{")?;
+    writeln!(out, "    rankdir=LR;")?;
+    writeln!(out, "    node [shape=box];")?;
+    for i in 0..graph.node_count() {
+        if let Some(v) = get_vertex_from_index(i, graph) {
+            let style = if halted.contains(&v.exchange) {
+                ", color=red, fontcolor=red, style=dashed"
+            } else {
+                ""
+            };
+            writeln!(
+                out,
+                "    {} [label=\"{}\\n{}\"{}];",
+                dot_id(&v),
+                dot_escape(&v.exchange),
+                dot_escape(&v.currency),
+                style
+            )?;
+        }
+    }
+    for e in graph.edge_indices() {
+        let edge = match get_edge_info(e, graph) {
+            Some(edge) => edge,
+            None => continue,
+        };
+        if edge.source == edge.destination {
+            continue;
+        }
+        let age = now
+            .map(|now| format_age(now - edge.timestamp))
+            .unwrap_or_default();
+        let style = match edge.kind {
+            EdgeKind::Trade => "color=\"#1f77b4\"",
+            EdgeKind::Transfer => "color=\"#999999\", style=dashed",
+        };
+        writeln!(
+            out,
+            "    {} -> {} [label=\"{:?}\\n{}\", {}];",
+            dot_id(&edge.source),
+            dot_id(&edge.destination),
+            edge.rate,
+            age,
+            style
+        )?;
+    }
+    writeln!(out, "}}")?;
+    return out.flush();
+}
+
+pub fn write_rate_matrix(
+    path: &str,
+    graph: &Graph<String, Edge>,
+    rate: &DenseMatrix<f32>,
+) -> Result<(), String> {
+    /* Writes the best rate table from Floyd-Warshall as CSV. The first row and column hold the
+     * vertex names so the matrix can be read without knowing the vertex indices.
+     * A rate of 0 means the column can't be reached from the row.
+     */
+    let names: Vec<String> = (0..graph.node_count())
+        .map(|i| match get_vertex_from_index(i, graph) {
+            Some(v) => csv_field(&format!("{} {}", v.exchange, v.currency)),
+            None => String::new(),
+        })
+        .collect();
+
+    let mut out = create(path)?;
+    return write_rate_matrix_to(&mut out, &names, rate)
+        .map_err(|e| format!("Error writing {}: {}", path, e));
+}
+
+fn write_rate_matrix_to(
+    out: &mut BufWriter<File>,
+    names: &[String],
+    rate: &DenseMatrix<f32>,
+) -> io::Result<()> {
+    writeln!(out, ",{}", names.join(","))?;
+    for (i, name) in names.iter().enumerate() {
+        let row: Vec<String> = rate.row(i).iter().map(|r| format!("{:?}", r)).collect();
+        writeln!(out, "{},{}", name, row.join(","))?;
+    }
+    return out.flush();
+}
+
+pub fn write_spreads(path: &str, spreads: &[Spread]) -> Result<(), String> {
+    /* Writes the quotes from SPREADS as CSV with a header row, one row per pair per exchange
+     * plus the best quote for each pair across exchanges.
+     */
+    let mut out = create(path)?;
+    return write_spreads_to(&mut out, spreads)
+        .map_err(|e| format!("Error writing {}: {}", path, e));
+}
+
+fn write_spreads_to(out: &mut BufWriter<File>, spreads: &[Spread]) -> io::Result<()> {
+    writeln!(
+        out,
+        "exchange,base,quote,bid,ask,mid,spread_bps,round_trip,timestamp"
+    )?;
+    for spread in spreads {
+        writeln!(out, "{}", spread_csv(spread))?;
+    }
+    return out.flush();
+}
+
+fn create(path: &str) -> Result<BufWriter<File>, String> {
+    let file = File::create(path).map_err(|e| format!("Could not create {}: {}", path, e))?;
+    return Ok(BufWriter::new(file));
+}
+
+fn dot_id(v: &Vertex) -> String {
+    return format!(
+        "\"{}\"",
+        dot_escape(&format!("{} {}", v.exchange, v.currency))
+    );
+}
+
+fn dot_escape(input: &str) -> String {
+    return input.replace('\\', "\\\\").replace('"', "\\\"");
+}
@@ -4,17 +4,23 @@
 */
 
 use crate::datetime_helpers::is_more_recent;
-use crate::{Edge, PriceUpdate, Vertex, DEBUG, DEFAULT_EDGE_WEIGHT};
+use crate::io_helpers::EdgeInfo;
+use crate::log_helpers::{log_debug, log_enabled, LogLevel};
+use crate::{Edge, EdgeKind, PriceUpdate, Vertex, DEFAULT_EDGE_WEIGHT};
 use chrono::{DateTime, FixedOffset};
-use petgraph::graph::node_index;
-use petgraph::Graph;
+use petgraph::graph::{node_index, EdgeIndex};
+use petgraph::visit::EdgeRef;
+use petgraph::{Direction, Graph};
+use std::collections::HashMap;
 
-pub fn get_index_from_node(v: &Vertex, graph: &Graph<String, f32>) -> Option<usize> {
+pub fn get_index_from_node(v: &Vertex, graph: &Graph<String, Edge>) -> Option<usize> {
     /* Takes a target vertex and a graph as input and returns
      * the index of the vertex in the graph if it exists.
+     * The weight has to match exactly; "KRAKEN, BTC" is not "KRAKEN, BTCD".
      */
+    let node_str = vertex_string_format(v);
     for (i, item) in graph.raw_nodes().iter().enumerate() {
-        if let true = item.weight.contains(&vertex_string_format(&v)) {
+        if item.weight == node_str {
             return Some(i);
         }
     }
@@ -26,13 +32,36 @@ pub fn vertex_string_format(v: &Vertex) -> String {
      * Doing this mainly to make the graph easier to keep track of.
      */
     let node_str = format!("{}, {}", &v.exchange, &v.currency);
-    if DEBUG {
-        println!("{}", &node_str);
-    }
     return node_str;
 }
 
-pub fn graph_contains(v: &Vertex, g: &Graph<String, f32>) -> bool {
+pub fn get_vertex_from_index(index: usize, graph: &Graph<String, Edge>) -> Option<Vertex> {
+    /* The reverse of vertex_string_format. Looks up the vertex at the given index
+     * and splits its weight back into exchange and currency.
+     */
+    let weight = graph.node_weight(node_index(index))?;
+    let (exchange, currency) = weight.split_once(", ")?;
+    return Some(Vertex {
+        exchange: exchange.to_string(),
+        currency: currency.to_string(),
+    });
+}
+
+pub fn get_edge_info(e: EdgeIndex, graph: &Graph<String, Edge>) -> Option<EdgeInfo> {
+    /* Collects everything known about an edge: its endpoints and its weight.
+     */
+    let (source, destination) = graph.edge_endpoints(e)?;
+    let data = graph.edge_weight(e)?;
+    return Some(EdgeInfo {
+        source: get_vertex_from_index(source.index(), graph)?,
+        destination: get_vertex_from_index(destination.index(), graph)?,
+        rate: data.rate,
+        timestamp: data.timestamp,
+        kind: data.kind,
+    });
+}
+
+pub fn graph_contains(v: &Vertex, g: &Graph<String, Edge>) -> bool {
     /* Simple function that checks if a target vertex
      * exists in the graph.
      */
@@ -44,12 +73,174 @@ pub fn graph_contains(v: &Vertex, g: &Graph<String, f32>) -> bool {
     return false;
 }
 
+/* Finds vertices by name, every vertex holding a currency, and the edge between two
+ * vertices, without scanning the graph or a vertex's edge list. The functions below that
+ * change the graph keep it in step. Indices for a currency are kept in ascending order, the
+ * same order a scan of the graph would give.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct GraphIndex {
+    by_name: HashMap<String, usize>,
+    by_currency: HashMap<String, Vec<usize>>,
+    edges: HashMap<(usize, usize), EdgeIndex>,
+}
+
+impl GraphIndex {
+    pub fn get(&self, v: &Vertex) -> Option<usize> {
+        return self.by_name.get(&vertex_string_format(v)).copied();
+    }
+
+    pub fn find_edge(&self, u: usize, v: usize) -> Option<EdgeIndex> {
+        return self.edges.get(&(u, v)).copied();
+    }
+
+    pub fn with_currency(&self, currency: &str) -> &[usize] {
+        return self
+            .by_currency
+            .get(currency)
+            .map(|indices| indices.as_slice())
+            .unwrap_or(&[]);
+    }
+
+    fn insert(&mut self, v: &Vertex, index: usize) {
+        self.by_name.insert(vertex_string_format(v), index);
+        let indices = self.by_currency.entry(v.currency.clone()).or_default();
+        if let Err(i) = indices.binary_search(&index) {
+            indices.insert(i, index);
+        }
+    }
+
+    fn remove(&mut self, v: &Vertex, index: usize) {
+        self.by_name.remove(&vertex_string_format(v));
+        if let Some(indices) = self.by_currency.get_mut(&v.currency) {
+            if let Ok(i) = indices.binary_search(&index) {
+                indices.remove(i);
+            }
+            if indices.is_empty() {
+                self.by_currency.remove(&v.currency);
+            }
+        }
+    }
+}
+
+pub fn check_consistency(graph: &Graph<String, Edge>, index: &GraphIndex) -> Vec<String> {
+    /* Checks that the index agrees with the graph and that every edge's metadata agrees with
+     * the vertices it joins. Returns a description of each problem found.
+     */
+    let mut problems = Vec::new();
+    for i in 0..graph.node_count() {
+        let v = match get_vertex_from_index(i, graph) {
+            Some(v) => v,
+            None => {
+                problems.push(format!("Vertex {} has a malformed name", i));
+                continue;
+            }
+        };
+        let name = vertex_string_format(&v);
+        match index.get(&v) {
+            Some(j) if j == i => {}
+            Some(j) => problems.push(format!("Vertex {} is at {} but indexed at {}", name, i, j)),
+            None => problems.push(format!("Vertex {} is missing from the index", name)),
+        }
+        if index.with_currency(&v.currency).binary_search(&i).is_err() {
+            problems.push(format!("Vertex {} is missing from its currency", name));
+        }
+    }
+    let by_currency: usize = index
+        .by_currency
+        .values()
+        .map(|indices| indices.len())
+        .sum();
+    if index.by_name.len() != graph.node_count() || by_currency != graph.node_count() {
+        problems.push(format!(
+            "The index holds {} vertices by name and {} by currency, the graph {}",
+            index.by_name.len(),
+            by_currency,
+            graph.node_count()
+        ));
+    }
+
+    for e in graph.edge_indices() {
+        let (u, v) = match graph.edge_endpoints(e) {
+            Some((u, v)) => (u.index(), v.index()),
+            None => continue,
+        };
+        let source = get_vertex_from_index(u, graph);
+        let destination = get_vertex_from_index(v, graph);
+        let (source, destination) = match (source, destination) {
+            (Some(source), Some(destination)) => (source, destination),
+            _ => continue,
+        };
+        let name = format!(
+            "{} -> {}",
+            vertex_string_format(&source),
+            vertex_string_format(&destination)
+        );
+        if index.find_edge(u, v) != Some(e) {
+            problems.push(format!("Edge {} is not indexed at {}", name, e.index()));
+        }
+        let edge = &graph[e];
+        if edge.exchange != source.exchange {
+            problems.push(format!("Edge {} is recorded for {}", name, edge.exchange));
+        }
+        if !edge.rate.is_finite() || edge.rate <= 0.0 {
+            problems.push(format!("Edge {} has the rate {:?}", name, edge.rate));
+        }
+        match edge.kind {
+            EdgeKind::Trade if source.exchange != destination.exchange => {
+                problems.push(format!("Trade edge {} joins two exchanges", name))
+            }
+            EdgeKind::Transfer if source.currency != destination.currency => {
+                problems.push(format!("Transfer edge {} joins two currencies", name))
+            }
+            EdgeKind::Transfer if edge.rate != DEFAULT_EDGE_WEIGHT => problems.push(format!(
+                "Transfer edge {} has the rate {:?}",
+                name, edge.rate
+            )),
+            _ => {}
+        }
+    }
+    if index.edges.len() != graph.edge_count() {
+        problems.push(format!(
+            "The index holds {} edges, the graph {}",
+            index.edges.len(),
+            graph.edge_count()
+        ));
+    }
+    return problems;
+}
+
+pub fn add_vertex(v: &Vertex, graph: &mut Graph<String, Edge>, index: &mut GraphIndex) -> usize {
+    /* Returns the index of the vertex, adding it to the graph first if it is new.
+     */
+    if let Some(i) = index.get(v) {
+        return i;
+    }
+    let node_str = vertex_string_format(v);
+    log_debug(&format!("Adding vertex {}", node_str));
+    let i = graph.add_node(node_str).index();
+    index.insert(v, i);
+    return i;
+}
+
+fn add_edge(
+    u: usize,
+    v: usize,
+    edge: Edge,
+    graph: &mut Graph<String, Edge>,
+    index: &mut GraphIndex,
+) {
+    let e = graph.add_edge(node_index(u), node_index(v), edge);
+    index.edges.insert((u, v), e);
+}
+
 pub fn process_edges_same_currency(
+    vertex_index: usize,
     vertex: &Vertex,
-    edge_data: &Vec<Edge>,
     incoming_price_update: &PriceUpdate,
-    graph: &Graph<String, f32>,
-) -> (Graph<String, f32>, Vec<Edge>) {
+    graph: &mut Graph<String, Edge>,
+    index: &mut GraphIndex,
+) {
     /* First part of processing edges.
      * This function is responsible for adding edges between
      * all vertices that share the same currency. For any node that shares the same currency,
@@ -57,175 +248,308 @@ pub fn process_edges_same_currency(
      * by simply moving his currency from one exchange to another without converting anything.
      * This 1 to 1 exchange rate also applies to the current node. We create an edge to itself
      * to model that it technically also has a 1 to 1 exchange rate.
+     *
+     * Only the vertices holding the same currency are visited, so the cost doesn't grow with
+     * the rest of the graph. Edges that already exist are left alone.
      */
-    let mut edge_data = edge_data.clone();
-    let mut graph: Graph<String, f32> = graph.clone();
-
-    // new_edges exists because we can't update graph until we're finished iterating over it.
-    let mut new_edges: Vec<(usize, usize, f32, DateTime<FixedOffset>)> = Vec::new();
-
-    for (index, node) in graph.raw_nodes().iter().enumerate() {
-        if node.weight.contains(&vertex.currency) {
-            let dest_index = get_index_from_node(&vertex, &graph);
-
-            let edge_to_dest =
-                dest_index.and_then(|i| graph.find_edge(node_index(index), node_index(i)));
-
-            match edge_to_dest {
-                None => {
-                    dest_index.map(|dest_index| {
-                        new_edges.push((
-                            index,
-                            dest_index,
-                            DEFAULT_EDGE_WEIGHT,
-                            incoming_price_update.timestamp,
-                        ))
-                    });
-                    if let Some(i) = dest_index {
-                        edge_data.push(Edge {
-                            source_index: index,
-                            dest_index: i,
-                            rate: DEFAULT_EDGE_WEIGHT,
-                            timestamp: incoming_price_update.timestamp,
-                        });
-                    }
-                }
-                Some(e) => {
-                    if DEBUG {
-                        println!(
-                            "FOUND EDGE OF INDEX: {:?}, {:?}",
-                            e,
-                            edge_data[e.index()].timestamp
-                        );
-                    }
-                }
-            }
+    let peers = index.with_currency(&vertex.currency).to_vec();
+    for other in peers {
+        add_transfer_edge(other, vertex_index, incoming_price_update, graph, index);
+        // The edge to itself was handled above.
+        if other != vertex_index {
+            add_transfer_edge(vertex_index, other, incoming_price_update, graph, index);
+        }
+    }
+}
 
-            let edge_to_dest =
-                dest_index.and_then(|i| graph.find_edge(node_index(i), node_index(index)));
-
-            match edge_to_dest {
-                None => {
-                    dest_index.map(|dest_index| {
-                        new_edges.push((
-                            dest_index,
-                            index,
-                            DEFAULT_EDGE_WEIGHT,
-                            incoming_price_update.timestamp,
-                        ))
-                    });
-
-                    if let Some(i) = dest_index {
-                        edge_data.push(Edge {
-                            source_index: i,
-                            dest_index: index,
-                            rate: DEFAULT_EDGE_WEIGHT,
-                            timestamp: incoming_price_update.timestamp,
-                        });
-                    }
-                }
-                Some(e) => {
-                    if DEBUG {
-                        println!(
-                            "FOUND EDGE OF INDEX: {:?}, {:?}",
-                            e,
-                            edge_data[e.index()].timestamp
-                        );
-                    }
-                }
+fn add_transfer_edge(
+    source_index: usize,
+    dest_index: usize,
+    incoming_price_update: &PriceUpdate,
+    graph: &mut Graph<String, Edge>,
+    index: &mut GraphIndex,
+) {
+    match index.find_edge(source_index, dest_index) {
+        None => {
+            let exchange = match get_vertex_from_index(source_index, graph) {
+                Some(v) => v.exchange,
+                None => return,
+            };
+            let edge = Edge {
+                rate: DEFAULT_EDGE_WEIGHT,
+                timestamp: incoming_price_update.timestamp,
+                kind: EdgeKind::Transfer,
+                exchange,
+            };
+            add_edge(source_index, dest_index, edge, graph, index);
+        }
+        Some(e) => {
+            if log_enabled(LogLevel::Debug) {
+                eprintln!("FOUND EDGE OF INDEX: {:?}, {:?}", e, graph[e].timestamp);
             }
         }
     }
-    for e in &new_edges {
-        graph.update_edge(node_index(e.0), node_index(e.1), e.2);
-    }
-
-    return (graph, edge_data);
 }
 
 pub fn process_edges_between_two_nodes(
     source_node_index: usize,
     dest_node_index: usize,
     incoming_price_update: &PriceUpdate,
-    edge_data: &Vec<Edge>,
-    graph: &Graph<String, f32>,
-) -> (Graph<String, f32>, Vec<Edge>) {
+    graph: &mut Graph<String, Edge>,
+    index: &mut GraphIndex,
+) {
     /* This is the second part of processing edges.
      * This function is responsible for creating the edges
      * between the source node and destination node described in the incoming price update.
      * It will only update an edge if the timestamp is more recent than the existing edge.
      */
-    let mut graph = graph.clone();
-    let mut edge_data = edge_data.clone();
-
     let edge_forward = Edge {
-        source_index: source_node_index,
-        dest_index: dest_node_index,
         rate: incoming_price_update.forward_factor,
         timestamp: incoming_price_update.timestamp,
+        kind: EdgeKind::Trade,
+        exchange: incoming_price_update.exchange.clone(),
     };
     let edge_backward = Edge {
-        source_index: source_node_index,
-        dest_index: dest_node_index,
         rate: incoming_price_update.backward_factor,
         timestamp: incoming_price_update.timestamp,
+        kind: EdgeKind::Trade,
+        exchange: incoming_price_update.exchange.clone(),
     };
 
-    let edge_i = graph.find_edge(node_index(source_node_index), node_index(dest_node_index));
-    match edge_i {
-        None => {
-            graph.update_edge(
-                node_index(source_node_index),
-                node_index(dest_node_index),
-                incoming_price_update.forward_factor,
-            );
-            edge_data.push(edge_forward);
-        }
+    set_trade_edge(
+        source_node_index,
+        dest_node_index,
+        edge_forward,
+        graph,
+        index,
+    );
+    // Reverse destination and source to find backward edge.
+    set_trade_edge(
+        dest_node_index,
+        source_node_index,
+        edge_backward,
+        graph,
+        index,
+    );
+}
+
+fn set_trade_edge(
+    u: usize,
+    v: usize,
+    edge: Edge,
+    graph: &mut Graph<String, Edge>,
+    index: &mut GraphIndex,
+) {
+    match index.find_edge(u, v) {
+        None => add_edge(u, v, edge, graph, index),
         Some(e_curr) => {
             // if one exists, only update if the new rate is more recent.
-            if is_more_recent(
-                incoming_price_update.timestamp,
-                edge_data[e_curr.index()].timestamp,
-            ) {
-                graph.update_edge(
-                    node_index(source_node_index),
-                    node_index(dest_node_index),
-                    edge_forward.rate,
-                );
-                edge_data[e_curr.index()] = edge_forward;
+            if is_more_recent(edge.timestamp, graph[e_curr].timestamp) {
+                graph[e_curr] = edge;
             } else {
                 // Don't update if new update isn't more recent.
             }
         }
     }
+}
 
-    // Reverse destination and source to find backward edge.
-    let edge_i = graph.find_edge(node_index(dest_node_index), node_index(source_node_index));
-    match edge_i {
-        None => {
-            graph.update_edge(
-                node_index(dest_node_index),
-                node_index(source_node_index),
-                incoming_price_update.backward_factor,
-            );
-            edge_data.push(edge_backward);
+pub fn remove_pair(
+    source_node_index: usize,
+    dest_node_index: usize,
+    timestamp: DateTime<FixedOffset>,
+    graph: &mut Graph<String, Edge>,
+    index: &mut GraphIndex,
+) -> usize {
+    /* Removes the edges in both directions between two vertices, as long as their quotes are
+     * no newer than the removal. A quote received after the removal was issued is kept.
+     * Returns the number of edges removed.
+     */
+    let mut removed = 0;
+
+    for (u, v) in [
+        (source_node_index, dest_node_index),
+        (dest_node_index, source_node_index),
+    ] {
+        if let Some(e) = index.find_edge(u, v) {
+            if !is_more_recent(graph[e].timestamp, timestamp) {
+                remove_edge(graph, index, e);
+                removed += 1;
+            }
         }
-        Some(e_curr) => {
-            // if one exists, only update if the new rate is more recent.
-            if is_more_recent(
-                incoming_price_update.timestamp,
-                edge_data[e_curr.index()].timestamp,
-            ) {
-                graph.update_edge(
-                    node_index(dest_node_index),
-                    node_index(source_node_index),
-                    edge_backward.rate,
-                );
-                edge_data[e_curr.index()] = edge_backward;
-            } else {
-                // Don't update if new update isn't more recent.
+    }
+    return removed;
+}
+
+pub fn remove_vertex(
+    vertex: &Vertex,
+    graph: &mut Graph<String, Edge>,
+    index: &mut GraphIndex,
+) -> Option<usize> {
+    /* Removes a vertex along with every edge into or out of it.
+     * Returns the number of edges removed, or None if the vertex doesn't exist.
+     *
+     * petgraph removes a node by moving the last node into its index, so the entries in the
+     * index that pointed at the last node and its edges are moved along with it.
+     */
+    let i = index.get(vertex)?;
+    let mut removed = 0;
+    let n = node_index(i);
+
+    while let Some(e) = graph
+        .first_edge(n, Direction::Outgoing)
+        .or_else(|| graph.first_edge(n, Direction::Incoming))
+    {
+        remove_edge(graph, index, e);
+        removed += 1;
+    }
+
+    let last = graph.node_count() - 1;
+    let moved = get_vertex_from_index(last, graph);
+    let mut moved_edges: Vec<EdgeIndex> = graph
+        .edges_directed(node_index(last), Direction::Outgoing)
+        .chain(graph.edges_directed(node_index(last), Direction::Incoming))
+        .map(|edge| edge.id())
+        .collect();
+    // An edge from the last node to itself shows up in both directions.
+    moved_edges.sort();
+    moved_edges.dedup();
+    let moved_edges: Vec<(EdgeIndex, usize, usize)> = moved_edges
+        .into_iter()
+        .filter_map(|e| {
+            let (u, v) = graph.edge_endpoints(e)?;
+            Some((e, u.index(), v.index()))
+        })
+        .collect();
+    graph.remove_node(n);
+    index.remove(vertex, i);
+    if last != i {
+        if let Some(moved) = moved {
+            index.remove(&moved, last);
+            index.insert(&moved, i);
+        }
+        let renumber = |x: usize| if x == last { i } else { x };
+        for (_, u, v) in &moved_edges {
+            index.edges.remove(&(*u, *v));
+        }
+        for (e, u, v) in moved_edges {
+            index.edges.insert((renumber(u), renumber(v)), e);
+        }
+    }
+    return Some(removed);
+}
+
+fn remove_edge(graph: &mut Graph<String, Edge>, index: &mut GraphIndex, e: EdgeIndex) {
+    /* petgraph removes an edge by moving the last edge into its index, so the index is
+     * pointed at the moved edge's new place.
+     */
+    if let Some((u, v)) = graph.edge_endpoints(e) {
+        index.edges.remove(&(u.index(), v.index()));
+    }
+    let last = EdgeIndex::new(graph.edge_count() - 1);
+    if last != e {
+        if let Some((u, v)) = graph.edge_endpoints(last) {
+            index.edges.insert((u.index(), v.index()), e);
+        }
+    }
+    graph.remove_edge(e);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io_helpers::parse_line;
+    use crate::Message;
+
+    fn build(lines: &[&str]) -> (Graph<String, Edge>, GraphIndex) {
+        let mut graph = Graph::new();
+        let mut index = GraphIndex::default();
+        for line in lines {
+            let update = match parse_line(line) {
+                Ok(Some(Message::PriceUpdate(update))) => update,
+                _ => panic!("expected a price update"),
+            };
+            let mut vertices = Vec::new();
+            for currency in &[&update.source_currency, &update.destination_currency] {
+                let vertex = Vertex {
+                    exchange: update.exchange.clone(),
+                    currency: currency.to_string(),
+                };
+                let i = add_vertex(&vertex, &mut graph, &mut index);
+                process_edges_same_currency(i, &vertex, &update, &mut graph, &mut index);
+                vertices.push(i);
             }
+            process_edges_between_two_nodes(
+                vertices[0],
+                vertices[1],
+                &update,
+                &mut graph,
+                &mut index,
+            );
         }
+        return (graph, index);
+    }
+
+    const FEED: &[&str] = &[
+        "2017-11-01T09:42:23+00:00 KRAKEN BTC USD 1000.0 0.0009",
+        "2017-11-01T09:42:24+00:00 GDAX BTC USD 1001.0 0.0008",
+        "2017-11-01T09:42:25+00:00 KRAKEN ETH BTC 0.05 19.0",
+        "2017-11-01T09:42:26+00:00 GDAX ETH USD 52.0 0.019",
+    ];
+
+    fn assert_removes_cleanly(position: usize) {
+        let (mut graph, mut index) = build(FEED);
+        assert!(check_consistency(&graph, &index).is_empty());
+        let vertices: Vec<Vertex> = (0..graph.node_count())
+            .filter_map(|i| get_vertex_from_index(i, &graph))
+            .collect();
+        let removed = vertices[position].clone();
+        let edges = graph.edge_count();
+
+        let count = remove_vertex(&removed, &mut graph, &mut index).expect("the vertex");
+        assert!(count > 0);
+        assert_eq!(graph.edge_count(), edges - count);
+        assert_eq!(graph.node_count(), vertices.len() - 1);
+        assert_eq!(index.get(&removed), None);
+        assert_eq!(check_consistency(&graph, &index), Vec::<String>::new());
+        for kept in vertices.iter().filter(|v| **v != removed) {
+            let i = index.get(kept).expect("a vertex that was kept");
+            assert_eq!(get_vertex_from_index(i, &graph).as_ref(), Some(kept));
+        }
+    }
+
+    #[test]
+    fn removing_the_first_vertex_keeps_the_index_consistent() {
+        assert_removes_cleanly(0);
+    }
+
+    #[test]
+    fn removing_a_middle_vertex_keeps_the_index_consistent() {
+        let (graph, _) = build(FEED);
+        assert_removes_cleanly(graph.node_count() / 2);
+    }
+
+    #[test]
+    fn removing_the_last_vertex_keeps_the_index_consistent() {
+        let (graph, _) = build(FEED);
+        assert_removes_cleanly(graph.node_count() - 1);
+    }
+
+    #[test]
+    fn removing_an_edge_points_the_index_at_the_edge_moved_into_its_place() {
+        let (mut graph, mut index) = build(FEED);
+        let edges = graph.edge_count();
+        let removed = EdgeIndex::new(1);
+        let last = EdgeIndex::new(edges - 1);
+        let (ru, rv) = graph.edge_endpoints(removed).unwrap();
+        let (lu, lv) = graph.edge_endpoints(last).unwrap();
+        let moved = graph[last].clone();
+
+        remove_edge(&mut graph, &mut index, removed);
+        assert_eq!(graph.edge_count(), edges - 1);
+        assert_eq!(check_consistency(&graph, &index), Vec::<String>::new());
+        assert_eq!(index.find_edge(ru.index(), rv.index()), None);
+        assert_eq!(index.find_edge(lu.index(), lv.index()), Some(removed));
+        assert_eq!(graph.edge_endpoints(removed), Some((lu, lv)));
+        assert_eq!(graph[removed], moved);
     }
-    return (graph, edge_data);
 }
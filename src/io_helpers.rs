@@ -3,75 +3,1554 @@
 * (The Exchange Rate Path Problem)
 */
 
-use crate::{datetime_helpers, ExchangeRateRequest, PriceUpdate};
-use petgraph::graph::node_index;
-use petgraph::Graph;
+use crate::config_helpers::Config;
+use crate::datetime_helpers::format_age;
+use crate::symbol_helpers::{canonical_currency, canonical_exchange};
+use crate::{
+    datetime_helpers, EdgeKind, ExchangeRateRequest, Message, PairRemoval, PriceUpdate, Vertex,
+    REQUEST_HEADER, REQUEST_PARAMETERS, UPDATE_PARAMETERS,
+};
+use chrono::{DateTime, FixedOffset};
+use serde_json::json;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
 use std::str::SplitWhitespace;
 
-pub fn exchange_rate_request(input: SplitWhitespace) -> ExchangeRateRequest {
-    //    println!("GET EXCHANGE RATE FOR REQUEST:");
-    let mut request_array: [&str; 5] = [" "; 5];
-    let mut i = 0;
-    for elem in input {
-        request_array[i] = elem;
-        i += 1;
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Jsonl,
+    Csv,
+}
+
+pub fn parse_output_format(input: &str) -> Result<OutputFormat, String> {
+    match input.to_ascii_lowercase().as_str() {
+        "text" => Ok(OutputFormat::Text),
+        "jsonl" | "json" => Ok(OutputFormat::Jsonl),
+        "csv" => Ok(OutputFormat::Csv),
+        _ => Err(format!(
+            "Unknown output format '{}'. Expected one of: text, jsonl, csv",
+            input
+        )),
     }
-    let request = ExchangeRateRequest {
-        source_exchange: request_array[1].to_string(),
-        source_currency: request_array[2].to_string(),
-        destination_exchange: request_array[3].to_string(),
-        destination_currency: request_array[4].to_string(),
+}
+
+pub fn output_format_name(format: OutputFormat) -> &'static str {
+    match format {
+        OutputFormat::Text => "text",
+        OutputFormat::Jsonl => "jsonl",
+        OutputFormat::Csv => "csv",
+    }
+}
+
+/* The answer to an Exchange Rate Request. The path is None if the destination can't be reached.
+ * namespace is the one the request was answered from.
+ */
+#[derive(Clone, Debug)]
+pub struct RateResponse {
+    pub request: ExchangeRateRequest,
+    pub rate: f32,
+    pub path: Option<Vec<Vertex>>,
+    pub check: Option<PathCheck>,
+    pub hops: Option<Vec<Hop>>,
+    pub version: u64,
+    pub namespace: String,
+}
+
+/* The answers to a batch of requests, all taken from the same Floyd-Warshall result.
+ * version identifies the state of the graph they were answered from.
+ */
+#[derive(Clone, Debug)]
+pub struct BatchResponse {
+    pub version: u64,
+    pub results: Vec<Result<RateResponse, String>>,
+}
+
+/* One edge of a best rate path, for --detailed output. cumulative_rate is the product of the
+ * rates up to and including this hop and age is how old the quote is relative to the latest
+ * price update.
+ */
+#[derive(Clone, Debug)]
+pub struct Hop {
+    pub edge: EdgeInfo,
+    pub cumulative_rate: f32,
+    pub age: chrono::Duration,
+}
+
+/* The result of re-deriving a best rate from its path with --verify.
+ * path_rate is the product of the current edge rates along the path, or 0 if a hop on the
+ * path isn't an edge of the graph.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct PathCheck {
+    pub path_rate: f32,
+    pub matches: bool,
+}
+
+/* A single edge of the graph along with the metadata kept on it.
+ */
+#[derive(Clone, Debug)]
+pub struct EdgeInfo {
+    pub source: Vertex,
+    pub destination: Vertex,
+    pub rate: f32,
+    pub timestamp: DateTime<FixedOffset>,
+    pub kind: EdgeKind,
+}
+
+/* The latest quote for a pair on one exchange, or the best across exchanges. A pair is quoted
+ * in the direction whose rate is the larger, e.g. BTC/USD rather than USD/BTC, so bid is what
+ * one base fetches in the quote currency and ask is what one base costs. round_trip is
+ * forward * backward, what is left of one unit after trading it there and back.
+ */
+#[derive(Clone, Debug)]
+pub struct Spread {
+    pub exchange: String,
+    pub base: String,
+    pub quote: String,
+    pub bid: f32,
+    pub ask: f32,
+    pub mid: f32,
+    pub spread_bps: f32,
+    pub round_trip: f32,
+    pub timestamp: DateTime<FixedOffset>,
+}
+
+/* One way of turning the source currency into the destination currency for BEST_VENUE: start
+ * on source_exchange and end on destination_exchange. rate is the best rate when transfers are
+ * allowed, taken from the all-pairs tables, and path the route to it. direct is the rate of the
+ * trade edge between the two currencies, which only exists when both exchanges are the same.
+ */
+#[derive(Clone, Debug)]
+pub struct Venue {
+    pub source_exchange: String,
+    pub destination_exchange: String,
+    pub rate: f32,
+    pub direct: Option<f32>,
+    pub path: Option<Vec<Vertex>>,
+}
+
+/* The answer to BEST_VENUE, with the venues ranked best rate first.
+ */
+#[derive(Clone, Debug)]
+pub struct VenueReport {
+    pub source_currency: String,
+    pub destination_currency: String,
+    pub venues: Vec<Venue>,
+    pub version: u64,
+    pub namespace: String,
+}
+
+impl VenueReport {
+    pub fn best_direct(&self) -> Option<&Venue> {
+        /* The exchange with the best direct rate, if any exchange trades the pair.
+         */
+        return self
+            .venues
+            .iter()
+            .filter(|v| v.direct.is_some())
+            .max_by(|a, b| a.direct.partial_cmp(&b.direct).unwrap_or(Ordering::Equal));
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GraphStats {
+    pub namespace: String,
+    pub vertices: usize,
+    pub edges: usize,
+    pub components: usize,
+    pub last_update: Option<DateTime<FixedOffset>>,
+    pub version: u64,
+    pub halted: Vec<String>,
+}
+
+/* The result of CHECK_CONSISTENCY. The namespace is consistent when problems is empty.
+ */
+#[derive(Clone, Debug)]
+pub struct ConsistencyReport {
+    pub namespace: String,
+    pub vertices: usize,
+    pub edges: usize,
+    pub problems: Vec<String>,
+}
+
+/* One trade simulated by the backtest command. expected_rate is the rate round the cycle when
+ * it was detected and executed_rate the rate once the latency had passed, or None if the cycle
+ * was broken by then and the trade was missed. pnl is in the currency the cycle starts from.
+ */
+#[derive(Clone, Debug)]
+pub struct BacktestTrade {
+    pub detected: DateTime<FixedOffset>,
+    pub executed: DateTime<FixedOffset>,
+    pub path: Vec<Vertex>,
+    pub expected_rate: f64,
+    pub executed_rate: Option<f64>,
+    pub pnl: f64,
+}
+
+/* The result of the backtest command. pnl adds up the trades by currency. durations are how
+ * long each opportunity stayed profitable, and open counts the ones still profitable at the
+ * end of the data.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct BacktestReport {
+    pub size: f64,
+    pub updates: usize,
+    pub opportunities: usize,
+    pub trades: Vec<BacktestTrade>,
+    pub pnl: BTreeMap<String, f64>,
+    pub durations: Vec<chrono::Duration>,
+    pub open: usize,
+}
+
+impl BacktestReport {
+    pub fn missed(&self) -> usize {
+        return self
+            .trades
+            .iter()
+            .filter(|t| t.executed_rate.is_none())
+            .count();
+    }
+}
+
+pub enum Response {
+    BestRate(RateResponse),
+    Exchanges(Vec<String>),
+    Currencies(Vec<String>),
+    Edges(Vec<EdgeInfo>),
+    Edge(EdgeInfo),
+    Spreads(Vec<Spread>),
+    BestVenue(VenueReport),
+    Stats(GraphStats),
+    Exported(String),
+    Metrics(String),
+    Batch(BatchResponse),
+    Removed(usize),
+    ExchangeStatus(String, bool),
+    Config(Box<Config>),
+    Reloaded,
+    Using(String),
+    Namespaces(Vec<String>),
+    Consistency(ConsistencyReport),
+    Backtest(BacktestReport),
+}
+
+/* Parses the lines of one input stream. Requests between BATCH_BEGIN and BATCH_END are held
+ * back and returned together as a single Message::Batch when the batch ends. A line holding a
+ * JSON array of requests is a batch on its own.
+ */
+#[derive(Default)]
+pub struct LineParser {
+    batch: Option<Vec<ExchangeRateRequest>>,
+}
+
+impl LineParser {
+    pub fn new() -> LineParser {
+        return LineParser { batch: None };
+    }
+
+    pub fn parse(&mut self, input: &str) -> Result<Option<Message>, String> {
+        let trimmed = input.trim();
+        if trimmed.starts_with('[') {
+            if self.batch.is_some() {
+                return Err("JSON batch inside a batch".to_string());
+            }
+            let requests: Vec<ExchangeRateRequest> =
+                serde_json::from_str(trimmed).map_err(|e| format!("Invalid JSON batch: {}", e))?;
+            let requests = requests
+                .into_iter()
+                .map(|r| ExchangeRateRequest {
+                    source_exchange: canonical_exchange(&r.source_exchange),
+                    source_currency: canonical_currency(&r.source_currency),
+                    destination_exchange: canonical_exchange(&r.destination_exchange),
+                    destination_currency: canonical_currency(&r.destination_currency),
+                    namespace: r.namespace,
+                })
+                .collect();
+            return Ok(Some(Message::Batch(requests)));
+        }
+        match trimmed {
+            "BATCH_BEGIN" => {
+                if self.batch.is_some() {
+                    return Err("BATCH_BEGIN inside a batch".to_string());
+                }
+                self.batch = Some(Vec::new());
+                return Ok(None);
+            }
+            "BATCH_END" => match self.batch.take() {
+                Some(requests) => return Ok(Some(Message::Batch(requests))),
+                None => return Err("BATCH_END without BATCH_BEGIN".to_string()),
+            },
+            _ => {}
+        }
+
+        let message = parse_line(input)?;
+        match (&mut self.batch, message) {
+            (Some(batch), Some(Message::ExchangeRateRequest(request))) => {
+                batch.push(request);
+                return Ok(None);
+            }
+            (Some(_), Some(_)) => {
+                return Err("Only Exchange Rate Requests are allowed in a batch".to_string())
+            }
+            (_, message) => return Ok(message),
+        }
+    }
+
+    pub fn finish(&mut self) -> Result<(), String> {
+        /* Called at the end of the stream. A batch that was never closed is dropped.
+         */
+        match self.batch.take() {
+            Some(requests) => Err(format!(
+                "Input ended inside a batch; {} requests were not answered",
+                requests.len()
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn parse_line(input: &str) -> Result<Option<Message>, String> {
+    /* Works out what kind of line this is from its first token and parses it.
+     * Anything that isn't a known command is treated as a Price Update.
+     * Blank lines and lines starting with '#' are ignored so recorded files can carry comments.
+     */
+    let trimmed = input.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return Ok(None);
+    }
+    let tokens: Vec<&str> = trimmed.split_whitespace().collect();
+
+    let message = match tokens[0] {
+        REQUEST_HEADER => {
+            Message::ExchangeRateRequest(exchange_rate_request(trimmed.split_whitespace())?)
+        }
+        "LIST_EXCHANGES" => {
+            expect_parameters(&tokens, 1)?;
+            Message::ListExchanges
+        }
+        "LIST_CURRENCIES" => {
+            expect_parameters(&tokens, 1)?;
+            Message::ListCurrencies
+        }
+        "CHECK_CONSISTENCY" => {
+            expect_parameters(&tokens, 1)?;
+            Message::CheckConsistency
+        }
+        "LIST_NAMESPACES" => {
+            expect_parameters(&tokens, 1)?;
+            Message::ListNamespaces
+        }
+        "USE" => {
+            expect_parameters(&tokens, 2)?;
+            Message::Use(tokens[1].to_string())
+        }
+        "LIST_EDGES" => match tokens.len() {
+            1 => Message::ListEdges(None),
+            2 => Message::ListEdges(Some(canonical_exchange(tokens[1]))),
+            _ => return Err("Usage: LIST_EDGES [exchange]".to_string()),
+        },
+        "SPREADS" => match tokens.len() {
+            1 => Message::Spreads(None),
+            2 => Message::Spreads(Some(canonical_exchange(tokens[1]))),
+            _ => return Err("Usage: SPREADS [exchange]".to_string()),
+        },
+        "BEST_VENUE" => {
+            if tokens.len() != 3 {
+                return Err(
+                    "Usage: BEST_VENUE <source_currency> <destination_currency>".to_string()
+                );
+            }
+            Message::BestVenue(canonical_currency(tokens[1]), canonical_currency(tokens[2]))
+        }
+        "SHOW_EDGE" => {
+            if tokens.len() != 5 {
+                return Err(
+                    "Usage: SHOW_EDGE <exchange> <currency> <exchange> <currency>".to_string(),
+                );
+            }
+            Message::ShowEdge(
+                Vertex {
+                    exchange: canonical_exchange(tokens[1]),
+                    currency: canonical_currency(tokens[2]),
+                },
+                Vertex {
+                    exchange: canonical_exchange(tokens[3]),
+                    currency: canonical_currency(tokens[4]),
+                },
+            )
+        }
+        "STATS" => {
+            expect_parameters(&tokens, 1)?;
+            Message::Stats
+        }
+        "METRICS" => {
+            expect_parameters(&tokens, 1)?;
+            Message::Metrics
+        }
+        "PAIR_REMOVE" => {
+            if tokens.len() != 5 {
+                return Err(
+                    "Usage: PAIR_REMOVE <timestamp> <exchange> <currency> <currency>".to_string(),
+                );
+            }
+            let removal = PairRemoval {
+                timestamp: datetime_helpers::get_datetime_from_string(tokens[1])?,
+                exchange: canonical_exchange(tokens[2]),
+                source_currency: canonical_currency(tokens[3]),
+                destination_currency: canonical_currency(tokens[4]),
+            };
+            // The only edge between a currency and itself is the transfer edge to itself.
+            if removal.source_currency == removal.destination_currency {
+                return Err("PAIR_REMOVE needs two different currencies".to_string());
+            }
+            Message::PairRemove(removal)
+        }
+        "VERTEX_REMOVE" => {
+            if tokens.len() != 3 {
+                return Err("Usage: VERTEX_REMOVE <exchange> <currency>".to_string());
+            }
+            Message::VertexRemove(Vertex {
+                exchange: canonical_exchange(tokens[1]),
+                currency: canonical_currency(tokens[2]),
+            })
+        }
+        "EXCHANGE_HALT" => {
+            expect_parameters(&tokens, 2)?;
+            Message::ExchangeHalt(canonical_exchange(tokens[1]))
+        }
+        "EXCHANGE_RESUME" => {
+            expect_parameters(&tokens, 2)?;
+            Message::ExchangeResume(canonical_exchange(tokens[1]))
+        }
+        "CONFIG_SHOW" => {
+            expect_parameters(&tokens, 1)?;
+            Message::ConfigShow
+        }
+        "CONFIG_RELOAD" => {
+            expect_parameters(&tokens, 1)?;
+            Message::ConfigReload
+        }
+        "EXPORT_DOT" => {
+            expect_parameters(&tokens, 2)?;
+            Message::ExportDot(tokens[1].to_string())
+        }
+        "EXPORT_MATRIX" => {
+            expect_parameters(&tokens, 2)?;
+            Message::ExportMatrix(tokens[1].to_string())
+        }
+        "EXPORT_SPREADS" => {
+            expect_parameters(&tokens, 2)?;
+            Message::ExportSpreads(tokens[1].to_string())
+        }
+        _ if tokens.len() == UPDATE_PARAMETERS => {
+            Message::PriceUpdate(price_update(trimmed.split_whitespace())?)
+        }
+        _ => {
+            return Err(
+                "Inputs must follow the form of a Price Update or Exchange Rate Request"
+                    .to_string(),
+            )
+        }
     };
-    return request;
+    return Ok(Some(message));
 }
 
-pub fn price_update(input: SplitWhitespace) -> PriceUpdate {
-    //    println!("Incoming price update:");
-    let mut price_update_array: [&str; 6] = [" "; 6];
-    let mut i = 0;
+fn expect_parameters(tokens: &[&str], expected: usize) -> Result<(), String> {
+    if tokens.len() != expected {
+        return Err(format!(
+            "{} takes {} parameters, got {}",
+            tokens[0],
+            expected - 1,
+            tokens.len() - 1
+        ));
+    }
+    return Ok(());
+}
 
-    for elem in input {
-        price_update_array[i] = elem;
-        i += 1;
+pub fn exchange_rate_request(input: SplitWhitespace) -> Result<ExchangeRateRequest, String> {
+    let request_array: Vec<&str> = input.collect();
+    // An optional last parameter names the namespace to answer from.
+    if request_array.len() != REQUEST_PARAMETERS && request_array.len() != REQUEST_PARAMETERS + 1 {
+        return Err("Error in input. Incorrect number of parameters.".to_string());
     }
-    if i != 6 {
-        println!("Error in input. Incorrect number of parameters.");
-        //error
+    let request = ExchangeRateRequest {
+        source_exchange: canonical_exchange(request_array[1]),
+        source_currency: canonical_currency(request_array[2]),
+        destination_exchange: canonical_exchange(request_array[3]),
+        destination_currency: canonical_currency(request_array[4]),
+        namespace: request_array.get(5).map(|n| n.to_string()),
+    };
+    return Ok(request);
+}
+
+pub fn price_update(input: SplitWhitespace) -> Result<PriceUpdate, String> {
+    let price_update_array: Vec<&str> = input.collect();
+    if price_update_array.len() != UPDATE_PARAMETERS {
+        return Err("Error in input. Incorrect number of parameters.".to_string());
     }
-    let pupdate = get_node(price_update_array);
-    return pupdate;
+    return get_node(&price_update_array);
 }
 
-pub fn get_node(input_array: [&str; 6]) -> PriceUpdate {
+pub fn get_node(input_array: &[&str]) -> Result<PriceUpdate, String> {
     let output_price_update = PriceUpdate {
-        timestamp: datetime_helpers::get_datetime_from_string(input_array[0]),
-        exchange: input_array[1].to_string(),
-        source_currency: input_array[2].to_string(),
-        destination_currency: input_array[3].to_string(),
-        forward_factor: input_array[4].parse().unwrap(),
-        backward_factor: input_array[5].parse().unwrap(),
+        timestamp: datetime_helpers::get_datetime_from_string(input_array[0])?,
+        exchange: canonical_exchange(input_array[1]),
+        source_currency: canonical_currency(input_array[2]),
+        destination_currency: canonical_currency(input_array[3]),
+        forward_factor: parse_factor(input_array[4])?,
+        backward_factor: parse_factor(input_array[5])?,
     };
-    return output_price_update;
+    return Ok(output_price_update);
+}
+
+fn parse_factor(input: &str) -> Result<f32, String> {
+    /* Rates must be positive, finite numbers for the products in Floyd-Warshall to make sense.
+     */
+    let factor: f32 = input
+        .parse()
+        .map_err(|_| format!("Invalid rate '{}'", input))?;
+    if !factor.is_finite() || factor <= 0.0 {
+        return Err(format!("Rate must be a positive number, got '{}'", input));
+    }
+    return Ok(factor);
 }
 
-pub fn print_results_part_one(rate_request: &ExchangeRateRequest, best_rate: &f32) {
-    println!(
-        "BEST_RATES_BEGIN <{}> <{}> <{}> <{}> <{:?}> ",
+pub fn open_input(path: &str) -> Result<Box<dyn BufRead + Send>, String> {
+    /* Opens an input file for reading. '-' means stdin.
+     */
+    if path == "-" {
+        return Ok(Box::new(BufReader::new(io::stdin())));
+    }
+    let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+    return Ok(Box::new(BufReader::new(file)));
+}
+
+pub fn open_output(path: &Option<String>) -> Result<Box<dyn Write + Send>, String> {
+    /* Opens the results destination. Defaults to stdout.
+     */
+    match path {
+        Some(p) => {
+            let file = File::create(p).map_err(|e| format!("Could not create {}: {}", p, e))?;
+            Ok(Box::new(io::BufWriter::new(file)))
+        }
+        None => Ok(Box::new(io::stdout())),
+    }
+}
+
+/* Writes responses in the selected format.
+ * text  - the BEST_RATES_BEGIN/BEST_RATES_END blocks described in the brief.
+ * jsonl - one JSON object per response.
+ * csv   - one row per response. The first column names the record type.
+ */
+pub struct Output {
+    format: OutputFormat,
+    writer: Box<dyn Write + Send>,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat, writer: Box<dyn Write + Send>) -> Output {
+        return Output { format, writer };
+    }
+
+    pub fn write_response(&mut self, response: &Response) -> io::Result<()> {
+        match response {
+            Response::BestRate(r) => self.write_rate_response(r)?,
+            Response::Exchanges(e) => self.write_names("EXCHANGES", "exchange", e)?,
+            Response::Currencies(c) => self.write_names("CURRENCIES", "currency", c)?,
+            Response::Edges(edges) => self.write_edges(edges)?,
+            Response::Edge(edge) => self.write_edge(edge)?,
+            Response::Spreads(spreads) => self.write_spreads(spreads)?,
+            Response::BestVenue(report) => self.write_best_venue(report)?,
+            Response::Stats(stats) => self.write_stats(stats)?,
+            Response::Exported(path) => match self.format {
+                OutputFormat::Text => writeln!(self.writer, "EXPORTED {}", path)?,
+                OutputFormat::Jsonl => writeln!(
+                    self.writer,
+                    "{}",
+                    json!({ "type": "exported", "path": path })
+                )?,
+                OutputFormat::Csv => writeln!(self.writer, "exported,{}", csv_field(path))?,
+            },
+            Response::Metrics(text) => self.write_metrics(text)?,
+            Response::Batch(batch) => self.write_batch(batch)?,
+            Response::ExchangeStatus(exchange, halted) => {
+                let status = if *halted { "HALTED" } else { "RESUMED" };
+                match self.format {
+                    OutputFormat::Text => writeln!(self.writer, "{} {}", status, exchange)?,
+                    OutputFormat::Jsonl => writeln!(
+                        self.writer,
+                        "{}",
+                        json!({ "type": "exchange_status", "exchange": exchange, "halted": halted })
+                    )?,
+                    OutputFormat::Csv => writeln!(
+                        self.writer,
+                        "exchange_status,{},{}",
+                        csv_field(exchange),
+                        halted
+                    )?,
+                }
+            }
+            Response::Config(config) => self.write_config(config)?,
+            Response::Reloaded => match self.format {
+                OutputFormat::Text => writeln!(self.writer, "CONFIG_RELOADED")?,
+                OutputFormat::Jsonl => {
+                    writeln!(self.writer, "{}", json!({ "type": "config_reloaded" }))?
+                }
+                OutputFormat::Csv => writeln!(self.writer, "config_reloaded")?,
+            },
+            Response::Using(namespace) => match self.format {
+                OutputFormat::Text => writeln!(self.writer, "USING {}", namespace)?,
+                OutputFormat::Jsonl => writeln!(
+                    self.writer,
+                    "{}",
+                    json!({ "type": "using", "namespace": namespace })
+                )?,
+                OutputFormat::Csv => writeln!(self.writer, "using,{}", csv_field(namespace))?,
+            },
+            Response::Namespaces(n) => self.write_names("NAMESPACES", "namespace", n)?,
+            Response::Consistency(report) => self.write_consistency(report)?,
+            Response::Backtest(report) => self.write_backtest(report)?,
+            Response::Removed(edges) => match self.format {
+                OutputFormat::Text => writeln!(self.writer, "REMOVED {}", edges)?,
+                OutputFormat::Jsonl => writeln!(
+                    self.writer,
+                    "{}",
+                    json!({ "type": "removed", "edges": edges })
+                )?,
+                OutputFormat::Csv => writeln!(self.writer, "removed,{}", edges)?,
+            },
+        }
+        return self.writer.flush();
+    }
+
+    pub fn write_error(&mut self, message: &str) -> io::Result<()> {
+        match self.format {
+            OutputFormat::Text => writeln!(self.writer, "ERROR {}", message)?,
+            OutputFormat::Jsonl => writeln!(
+                self.writer,
+                "{}",
+                json!({ "type": "error", "message": message })
+            )?,
+            OutputFormat::Csv => writeln!(self.writer, "error,{}", csv_field(message))?,
+        }
+        return self.writer.flush();
+    }
+
+    fn write_rate_response(&mut self, response: &RateResponse) -> io::Result<()> {
+        match self.format {
+            OutputFormat::Text => {
+                writeln!(
+                    self.writer,
+                    "{}",
+                    format_results_part_one(&response.request, &response.rate, response.version)
+                )?;
+                match &response.hops {
+                    Some(hops) if !hops.is_empty() => {
+                        for hop in hops {
+                            writeln!(self.writer, "{}", format_hop(hop))?;
+                        }
+                    }
+                    _ => {
+                        for line in format_results_part_two(&response.path) {
+                            writeln!(self.writer, "{}", line)?;
+                        }
+                    }
+                }
+                writeln!(self.writer, "BEST_RATES_END")?;
+                match response.check {
+                    Some(check) if check.matches => {
+                        writeln!(self.writer, "VERIFY_OK {:?}", check.path_rate)?
+                    }
+                    Some(check) => writeln!(
+                        self.writer,
+                        "VERIFY_MISMATCH {:?} {:?}",
+                        response.rate, check.path_rate
+                    )?,
+                    None => {}
+                }
+            }
+            OutputFormat::Jsonl => writeln!(self.writer, "{}", rate_json(response))?,
+            OutputFormat::Csv => {
+                let path = match &response.path {
+                    Some(p) => p
+                        .iter()
+                        .map(|v| format!("{} {}", v.exchange, v.currency))
+                        .collect::<Vec<String>>()
+                        .join("|"),
+                    None => String::new(),
+                };
+                write!(
+                    self.writer,
+                    "best_rate,{},{},{},{},{:?},{},{}",
+                    csv_field(&response.request.source_exchange),
+                    csv_field(&response.request.source_currency),
+                    csv_field(&response.request.destination_exchange),
+                    csv_field(&response.request.destination_currency),
+                    response.rate,
+                    csv_field(&path),
+                    response.version
+                )?;
+                // With --verify the path rate and whether it matches are added as two columns.
+                if let Some(check) = response.check {
+                    write!(self.writer, ",{:?},{}", check.path_rate, check.matches)?;
+                }
+                writeln!(self.writer)?;
+                for hop in response.hops.iter().flatten() {
+                    writeln!(self.writer, "{}", hop_csv(hop))?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn write_config(&mut self, config: &Config) -> io::Result<()> {
+        let value = serde_json::to_value(config).map_err(io::Error::other)?;
+        match self.format {
+            OutputFormat::Text => {
+                let text = toml::to_string(config).map_err(io::Error::other)?;
+                writeln!(self.writer, "CONFIG_BEGIN")?;
+                write!(self.writer, "{}", text)?;
+                writeln!(self.writer, "CONFIG_END")?;
+            }
+            OutputFormat::Jsonl => writeln!(
+                self.writer,
+                "{}",
+                json!({ "type": "config", "config": value })
+            )?,
+            OutputFormat::Csv => {
+                // One row per setting, with nested keys joined by dots, e.g. fees.default.
+                let mut rows = Vec::new();
+                flatten_json("", &value, &mut rows);
+                for (key, value) in rows {
+                    writeln!(
+                        self.writer,
+                        "config,{},{}",
+                        csv_field(&key),
+                        csv_field(&value)
+                    )?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn write_batch(&mut self, batch: &BatchResponse) -> io::Result<()> {
+        match self.format {
+            OutputFormat::Text => {
+                writeln!(
+                    self.writer,
+                    "BATCH_BEGIN {} {}",
+                    batch.version,
+                    batch.results.len()
+                )?;
+                for result in &batch.results {
+                    match result {
+                        Ok(response) => self.write_rate_response(response)?,
+                        Err(e) => writeln!(self.writer, "ERROR {}", e)?,
+                    }
+                }
+                writeln!(self.writer, "BATCH_END")?;
+            }
+            OutputFormat::Jsonl => {
+                let results: Vec<serde_json::Value> = batch
+                    .results
+                    .iter()
+                    .map(|result| match result {
+                        Ok(response) => rate_json(response),
+                        Err(e) => json!({ "type": "error", "message": e }),
+                    })
+                    .collect();
+                let object = json!({
+                    "type": "batch",
+                    "version": batch.version,
+                    "results": results,
+                });
+                writeln!(self.writer, "{}", object)?;
+            }
+            OutputFormat::Csv => {
+                writeln!(
+                    self.writer,
+                    "batch_begin,{},{}",
+                    batch.version,
+                    batch.results.len()
+                )?;
+                for result in &batch.results {
+                    match result {
+                        Ok(response) => self.write_rate_response(response)?,
+                        Err(e) => writeln!(self.writer, "error,{}", csv_field(e))?,
+                    }
+                }
+                writeln!(self.writer, "batch_end")?;
+            }
+        }
+        return Ok(());
+    }
+
+    fn write_names(&mut self, header: &str, record: &str, names: &[String]) -> io::Result<()> {
+        match self.format {
+            OutputFormat::Text => {
+                writeln!(self.writer, "{}_BEGIN {}", header, names.len())?;
+                for name in names {
+                    writeln!(self.writer, "{}", name)?;
+                }
+                writeln!(self.writer, "{}_END", header)?;
+            }
+            OutputFormat::Jsonl => {
+                let key = header.to_ascii_lowercase();
+                writeln!(
+                    self.writer,
+                    "{}",
+                    json!({ "type": key, key.as_str(): names })
+                )?;
+            }
+            OutputFormat::Csv => {
+                for name in names {
+                    writeln!(self.writer, "{},{}", record, csv_field(name))?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn write_edges(&mut self, edges: &[EdgeInfo]) -> io::Result<()> {
+        match self.format {
+            OutputFormat::Text => {
+                writeln!(self.writer, "EDGES_BEGIN {}", edges.len())?;
+                for edge in edges {
+                    writeln!(self.writer, "{}", format_edge(edge))?;
+                }
+                writeln!(self.writer, "EDGES_END")?;
+            }
+            OutputFormat::Jsonl => {
+                let edges: Vec<serde_json::Value> = edges.iter().map(edge_json).collect();
+                writeln!(
+                    self.writer,
+                    "{}",
+                    json!({ "type": "edges", "edges": edges })
+                )?;
+            }
+            OutputFormat::Csv => {
+                for edge in edges {
+                    writeln!(self.writer, "{}", edge_csv(edge))?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn write_edge(&mut self, edge: &EdgeInfo) -> io::Result<()> {
+        match self.format {
+            OutputFormat::Text => writeln!(self.writer, "EDGE {}", format_edge(edge))?,
+            OutputFormat::Jsonl => {
+                let mut object = edge_json(edge);
+                object["type"] = json!("edge");
+                writeln!(self.writer, "{}", object)?;
+            }
+            OutputFormat::Csv => writeln!(self.writer, "{}", edge_csv(edge))?,
+        }
+        return Ok(());
+    }
+
+    fn write_best_venue(&mut self, report: &VenueReport) -> io::Result<()> {
+        let path = |venue: &Venue| venue.path.as_deref().map(format_path).unwrap_or_default();
+        let best_direct = report.best_direct();
+        match self.format {
+            OutputFormat::Text => {
+                writeln!(
+                    self.writer,
+                    "BEST_VENUE_BEGIN <{}> <{}> {} {}",
+                    report.source_currency,
+                    report.destination_currency,
+                    report.version,
+                    report.venues.len()
+                )?;
+                for (rank, venue) in report.venues.iter().enumerate() {
+                    let direct = match venue.direct {
+                        Some(rate) => format!("{:?}", rate),
+                        None => "none".to_string(),
+                    };
+                    writeln!(
+                        self.writer,
+                        "{} <{}> <{}> rate={:?} direct={} path={}",
+                        rank + 1,
+                        venue.source_exchange,
+                        venue.destination_exchange,
+                        venue.rate,
+                        direct,
+                        path(venue)
+                    )?;
+                }
+                if let Some(venue) = best_direct {
+                    writeln!(
+                        self.writer,
+                        "BEST_DIRECT <{}> {:?}",
+                        venue.source_exchange,
+                        venue.direct.unwrap_or_default()
+                    )?;
+                }
+                writeln!(self.writer, "BEST_VENUE_END")?;
+            }
+            OutputFormat::Jsonl => {
+                let venues: Vec<serde_json::Value> = report
+                    .venues
+                    .iter()
+                    .map(|venue| {
+                        let path = venue.path.as_ref().map(|p| {
+                            p.iter()
+                                .map(|v| json!({ "exchange": v.exchange, "currency": v.currency }))
+                                .collect::<Vec<_>>()
+                        });
+                        json!({
+                            "source_exchange": venue.source_exchange,
+                            "destination_exchange": venue.destination_exchange,
+                            "rate": json_rate(venue.rate),
+                            "direct": venue.direct.map(json_rate),
+                            "path": path,
+                        })
+                    })
+                    .collect();
+                writeln!(
+                    self.writer,
+                    "{}",
+                    json!({
+                        "type": "best_venue",
+                        "source_currency": report.source_currency,
+                        "destination_currency": report.destination_currency,
+                        "venues": venues,
+                        "best_direct": best_direct.map(|v| &v.source_exchange),
+                        "version": report.version,
+                        "namespace": report.namespace,
+                    })
+                )?;
+            }
+            OutputFormat::Csv => {
+                for (rank, venue) in report.venues.iter().enumerate() {
+                    let direct = venue
+                        .direct
+                        .map(|rate| format!("{:?}", rate))
+                        .unwrap_or_default();
+                    writeln!(
+                        self.writer,
+                        "best_venue,{},{},{},{},{},{:?},{},{},{}",
+                        rank + 1,
+                        csv_field(&report.source_currency),
+                        csv_field(&report.destination_currency),
+                        csv_field(&venue.source_exchange),
+                        csv_field(&venue.destination_exchange),
+                        venue.rate,
+                        direct,
+                        csv_field(&path(venue)),
+                        report.version
+                    )?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn write_spreads(&mut self, spreads: &[Spread]) -> io::Result<()> {
+        match self.format {
+            OutputFormat::Text => {
+                writeln!(self.writer, "SPREADS_BEGIN {}", spreads.len())?;
+                for spread in spreads {
+                    writeln!(
+                        self.writer,
+                        "<{}> <{}/{}> bid={:?} ask={:?} mid={:?} spread_bps={:?} round_trip={:?} <{}>",
+                        spread.exchange,
+                        spread.base,
+                        spread.quote,
+                        spread.bid,
+                        spread.ask,
+                        spread.mid,
+                        spread.spread_bps,
+                        spread.round_trip,
+                        spread.timestamp.to_rfc3339()
+                    )?;
+                }
+                writeln!(self.writer, "SPREADS_END")?;
+            }
+            OutputFormat::Jsonl => {
+                let spreads: Vec<serde_json::Value> = spreads
+                    .iter()
+                    .map(|spread| {
+                        json!({
+                            "exchange": spread.exchange,
+                            "base": spread.base,
+                            "quote": spread.quote,
+                            "bid": json_rate(spread.bid),
+                            "ask": json_rate(spread.ask),
+                            "mid": json_rate(spread.mid),
+                            "spread_bps": json_rate(spread.spread_bps),
+                            "round_trip": json_rate(spread.round_trip),
+                            "timestamp": spread.timestamp.to_rfc3339(),
+                        })
+                    })
+                    .collect();
+                writeln!(
+                    self.writer,
+                    "{}",
+                    json!({ "type": "spreads", "spreads": spreads })
+                )?;
+            }
+            OutputFormat::Csv => {
+                for spread in spreads {
+                    writeln!(self.writer, "spread,{}", spread_csv(spread))?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn write_metrics(&mut self, text: &str) -> io::Result<()> {
+        /* The text format is the Prometheus exposition format as served on /metrics.
+         * The other formats carry just the samples as name/value pairs.
+         */
+        let samples = text
+            .lines()
+            .filter(|l| !l.starts_with('#'))
+            .filter_map(|l| l.rsplit_once(' '));
+        match self.format {
+            OutputFormat::Text => {
+                writeln!(self.writer, "METRICS_BEGIN")?;
+                write!(self.writer, "{}", text)?;
+                writeln!(self.writer, "METRICS_END")?;
+            }
+            OutputFormat::Jsonl => {
+                let mut object = serde_json::Map::new();
+                for (name, value) in samples {
+                    let value: f64 = value.parse().unwrap_or(0.0);
+                    object.insert(name.to_string(), json!(value));
+                }
+                writeln!(
+                    self.writer,
+                    "{}",
+                    json!({ "type": "metrics", "metrics": object })
+                )?;
+            }
+            OutputFormat::Csv => {
+                for (name, value) in samples {
+                    writeln!(self.writer, "metric,{},{}", csv_field(name), value)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn write_consistency(&mut self, report: &ConsistencyReport) -> io::Result<()> {
+        match self.format {
+            OutputFormat::Text => {
+                let status = if report.problems.is_empty() {
+                    "CONSISTENT"
+                } else {
+                    "INCONSISTENT"
+                };
+                writeln!(
+                    self.writer,
+                    "{} vertices={} edges={} problems={} namespace={}",
+                    status,
+                    report.vertices,
+                    report.edges,
+                    report.problems.len(),
+                    report.namespace
+                )?;
+                for problem in &report.problems {
+                    writeln!(self.writer, "PROBLEM {}", problem)?;
+                }
+            }
+            OutputFormat::Jsonl => writeln!(
+                self.writer,
+                "{}",
+                json!({
+                    "type": "consistency",
+                    "consistent": report.problems.is_empty(),
+                    "vertices": report.vertices,
+                    "edges": report.edges,
+                    "problems": report.problems,
+                    "namespace": report.namespace,
+                })
+            )?,
+            OutputFormat::Csv => writeln!(
+                self.writer,
+                "consistency,{},{},{},{},{}",
+                report.problems.is_empty(),
+                report.vertices,
+                report.edges,
+                csv_field(&report.problems.join("|")),
+                csv_field(&report.namespace)
+            )?,
+        }
+        return Ok(());
+    }
+
+    fn write_backtest(&mut self, report: &BacktestReport) -> io::Result<()> {
+        let closed = report.durations.len();
+        let (min, max) = (report.durations.iter().min(), report.durations.iter().max());
+        let mean = report
+            .durations
+            .iter()
+            .fold(chrono::Duration::zero(), |sum, d| sum + *d)
+            / closed.max(1) as i32;
+        match self.format {
+            OutputFormat::Text => {
+                writeln!(
+                    self.writer,
+                    "BACKTEST_BEGIN updates={} opportunities={} trades={} missed={} size={}",
+                    report.updates,
+                    report.opportunities,
+                    report.trades.len(),
+                    report.missed(),
+                    report.size
+                )?;
+                for trade in &report.trades {
+                    let executed_rate = match trade.executed_rate {
+                        Some(rate) => format!("{}", rate),
+                        None => "missed".to_string(),
+                    };
+                    writeln!(
+                        self.writer,
+                        "TRADE detected={} executed={} expected={} actual={} pnl={} {} path={}",
+                        trade.detected.to_rfc3339(),
+                        trade.executed.to_rfc3339(),
+                        trade.expected_rate,
+                        executed_rate,
+                        trade.pnl,
+                        trade.path[0].currency,
+                        format_path(&trade.path)
+                    )?;
+                }
+                for (currency, pnl) in &report.pnl {
+                    writeln!(self.writer, "PNL {} {}", currency, pnl)?;
+                }
+                match (min, max) {
+                    (Some(min), Some(max)) => writeln!(
+                        self.writer,
+                        "DURATIONS closed={} open={} min={} mean={} max={}",
+                        closed,
+                        report.open,
+                        format_age(*min),
+                        format_age(mean),
+                        format_age(*max)
+                    )?,
+                    _ => writeln!(self.writer, "DURATIONS closed=0 open={}", report.open)?,
+                }
+                writeln!(self.writer, "BACKTEST_END")?;
+            }
+            OutputFormat::Jsonl => {
+                let trades: Vec<serde_json::Value> = report
+                    .trades
+                    .iter()
+                    .map(|trade| {
+                        json!({
+                            "detected": trade.detected.to_rfc3339(),
+                            "executed": trade.executed.to_rfc3339(),
+                            "expected_rate": trade.expected_rate,
+                            "executed_rate": trade.executed_rate,
+                            "pnl": trade.pnl,
+                            "currency": trade.path[0].currency,
+                            "path": trade
+                                .path
+                                .iter()
+                                .map(|v| json!({ "exchange": v.exchange, "currency": v.currency }))
+                                .collect::<Vec<_>>(),
+                        })
+                    })
+                    .collect();
+                let seconds = |d: Option<&chrono::Duration>| d.map(|d| d.num_seconds());
+                writeln!(
+                    self.writer,
+                    "{}",
+                    json!({
+                        "type": "backtest",
+                        "updates": report.updates,
+                        "opportunities": report.opportunities,
+                        "trades": trades,
+                        "missed": report.missed(),
+                        "size": report.size,
+                        "pnl": report.pnl,
+                        "durations": {
+                            "closed": closed,
+                            "open": report.open,
+                            "min_secs": seconds(min),
+                            "mean_secs": seconds(min.map(|_| &mean)),
+                            "max_secs": seconds(max),
+                        },
+                    })
+                )?
+            }
+            OutputFormat::Csv => {
+                writeln!(
+                    self.writer,
+                    "backtest,{},{},{},{},{}",
+                    report.updates,
+                    report.opportunities,
+                    report.trades.len(),
+                    report.missed(),
+                    report.size
+                )?;
+                for trade in &report.trades {
+                    let executed_rate = trade
+                        .executed_rate
+                        .map(|rate| rate.to_string())
+                        .unwrap_or_default();
+                    writeln!(
+                        self.writer,
+                        "backtest_trade,{},{},{},{},{},{},{}",
+                        trade.detected.to_rfc3339(),
+                        trade.executed.to_rfc3339(),
+                        trade.expected_rate,
+                        executed_rate,
+                        trade.pnl,
+                        csv_field(&trade.path[0].currency),
+                        csv_field(&format_path(&trade.path))
+                    )?;
+                }
+                for (currency, pnl) in &report.pnl {
+                    writeln!(self.writer, "backtest_pnl,{},{}", csv_field(currency), pnl)?;
+                }
+                let seconds = |d: Option<&chrono::Duration>| {
+                    d.map(|d| d.num_seconds().to_string()).unwrap_or_default()
+                };
+                writeln!(
+                    self.writer,
+                    "backtest_durations,{},{},{},{},{}",
+                    closed,
+                    report.open,
+                    seconds(min),
+                    seconds(min.map(|_| &mean)),
+                    seconds(max)
+                )?;
+            }
+        }
+        return Ok(());
+    }
+
+    fn write_stats(&mut self, stats: &GraphStats) -> io::Result<()> {
+        let last_update = stats.last_update.map(|t| t.to_rfc3339());
+        match self.format {
+            OutputFormat::Text => writeln!(
+                self.writer,
+                "STATS vertices={} edges={} components={} last_update={} version={} halted={} namespace={}",
+                stats.vertices,
+                stats.edges,
+                stats.components,
+                last_update.unwrap_or_else(|| "none".to_string()),
+                stats.version,
+                if stats.halted.is_empty() {
+                    "none".to_string()
+                } else {
+                    stats.halted.join(",")
+                },
+                stats.namespace
+            )?,
+            OutputFormat::Jsonl => writeln!(
+                self.writer,
+                "{}",
+                json!({
+                    "type": "stats",
+                    "vertices": stats.vertices,
+                    "edges": stats.edges,
+                    "components": stats.components,
+                    "last_update": last_update,
+                    "version": stats.version,
+                    "halted": stats.halted,
+                    "namespace": stats.namespace,
+                })
+            )?,
+            OutputFormat::Csv => writeln!(
+                self.writer,
+                "stats,{},{},{},{},{},{},{}",
+                stats.vertices,
+                stats.edges,
+                stats.components,
+                last_update.unwrap_or_default(),
+                stats.version,
+                csv_field(&stats.halted.join("|")),
+                csv_field(&stats.namespace)
+            )?,
+        }
+        return Ok(());
+    }
+}
+
+pub fn edge_kind_name(kind: EdgeKind) -> &'static str {
+    match kind {
+        EdgeKind::Trade => "TRADE",
+        EdgeKind::Transfer => "TRANSFER",
+    }
+}
+
+fn format_edge(edge: &EdgeInfo) -> String {
+    return format!(
+        "<{}, {}> <{}, {}> <{:?}> <{}> <{}>",
+        edge.source.exchange,
+        edge.source.currency,
+        edge.destination.exchange,
+        edge.destination.currency,
+        edge.rate,
+        edge.timestamp.to_rfc3339(),
+        edge_kind_name(edge.kind)
+    );
+}
+
+fn format_path(path: &[Vertex]) -> String {
+    /* The vertices of a path in order, joined like the path column of a best_rate row.
+     * A backtest cycle goes from the last one back to the first.
+     */
+    return path
+        .iter()
+        .map(|v| format!("{} {}", v.exchange, v.currency))
+        .collect::<Vec<String>>()
+        .join("|");
+}
+
+fn rate_json(response: &RateResponse) -> serde_json::Value {
+    let path = response.path.as_ref().map(|p| {
+        p.iter()
+            .map(|v| json!({ "exchange": v.exchange, "currency": v.currency }))
+            .collect::<Vec<_>>()
+    });
+    let mut object = json!({
+        "type": "best_rate",
+        "source_exchange": response.request.source_exchange,
+        "source_currency": response.request.source_currency,
+        "destination_exchange": response.request.destination_exchange,
+        "destination_currency": response.request.destination_currency,
+        "rate": json_rate(response.rate),
+        "path": path,
+        "version": response.version,
+        "namespace": response.namespace,
+    });
+    if let Some(hops) = &response.hops {
+        object["hops"] = hops.iter().map(hop_json).collect();
+    }
+    if let Some(check) = response.check {
+        object["verified"] = json!({
+            "path_rate": json_rate(check.path_rate),
+            "matches": check.matches,
+        });
+    }
+    return object;
+}
+
+fn flatten_json(prefix: &str, value: &serde_json::Value, rows: &mut Vec<(String, String)>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten_json(&key, value, rows);
+            }
+        }
+        serde_json::Value::Null => {}
+        serde_json::Value::String(s) => rows.push((prefix.to_string(), s.to_string())),
+        other => rows.push((prefix.to_string(), other.to_string())),
+    }
+}
+
+fn format_hop(hop: &Hop) -> String {
+    return format!(
+        "<{}, {}> <{}, {}> <{}> <{:?}> <{:?}> <{}> <{}>",
+        hop.edge.source.exchange,
+        hop.edge.source.currency,
+        hop.edge.destination.exchange,
+        hop.edge.destination.currency,
+        edge_kind_name(hop.edge.kind),
+        hop.edge.rate,
+        hop.cumulative_rate,
+        hop.edge.timestamp.to_rfc3339(),
+        format_age(hop.age)
+    );
+}
+
+fn hop_json(hop: &Hop) -> serde_json::Value {
+    let mut object = edge_json(&hop.edge);
+    object["cumulative_rate"] = json_rate(hop.cumulative_rate);
+    object["age_secs"] = json!(hop.age.num_seconds());
+    return object;
+}
+
+fn hop_csv(hop: &Hop) -> String {
+    return format!(
+        "hop,{},{},{},{},{},{:?},{:?},{},{}",
+        csv_field(&hop.edge.source.exchange),
+        csv_field(&hop.edge.source.currency),
+        csv_field(&hop.edge.destination.exchange),
+        csv_field(&hop.edge.destination.currency),
+        edge_kind_name(hop.edge.kind),
+        hop.edge.rate,
+        hop.cumulative_rate,
+        hop.edge.timestamp.to_rfc3339(),
+        hop.age.num_seconds()
+    );
+}
+
+fn edge_json(edge: &EdgeInfo) -> serde_json::Value {
+    return json!({
+        "source_exchange": edge.source.exchange,
+        "source_currency": edge.source.currency,
+        "destination_exchange": edge.destination.exchange,
+        "destination_currency": edge.destination.currency,
+        "rate": json_rate(edge.rate),
+        "timestamp": edge.timestamp.to_rfc3339(),
+        "kind": edge_kind_name(edge.kind),
+    });
+}
+
+fn edge_csv(edge: &EdgeInfo) -> String {
+    return format!(
+        "edge,{},{},{},{},{:?},{},{}",
+        csv_field(&edge.source.exchange),
+        csv_field(&edge.source.currency),
+        csv_field(&edge.destination.exchange),
+        csv_field(&edge.destination.currency),
+        edge.rate,
+        edge.timestamp.to_rfc3339(),
+        edge_kind_name(edge.kind)
+    );
+}
+
+pub fn spread_csv(spread: &Spread) -> String {
+    /* The columns of a spread, shared by the csv output format and EXPORT_SPREADS.
+     */
+    return format!(
+        "{},{},{},{:?},{:?},{:?},{:?},{:?},{}",
+        csv_field(&spread.exchange),
+        csv_field(&spread.base),
+        csv_field(&spread.quote),
+        spread.bid,
+        spread.ask,
+        spread.mid,
+        spread.spread_bps,
+        spread.round_trip,
+        spread.timestamp.to_rfc3339()
+    );
+}
+
+pub fn json_rate(rate: f32) -> serde_json::Value {
+    /* serde_json widens f32 to f64, which prints 0.0009 as 0.0008999999845400453.
+     * Going through the shortest f32 representation keeps JSON output matching the text output.
+     */
+    let widened: f64 = format!("{:?}", rate).parse().unwrap_or(rate as f64);
+    return json!(widened);
+}
+
+pub fn csv_field(input: &str) -> String {
+    /* Quotes a CSV field if it contains a separator, quote or newline.
+     */
+    if input.contains(',') || input.contains('"') || input.contains('\n') {
+        return format!("\"{}\"", input.replace('"', "\"\""));
+    }
+    return input.to_string();
+}
+
+pub fn format_results_part_one(
+    rate_request: &ExchangeRateRequest,
+    best_rate: &f32,
+    version: u64,
+) -> String {
+    return format!(
+        "BEST_RATES_BEGIN <{}> <{}> <{}> <{}> <{:?}> <{}>",
         rate_request.source_exchange,
         rate_request.source_currency,
         rate_request.destination_exchange,
         rate_request.destination_currency,
-        best_rate
+        best_rate,
+        version
     );
 }
-pub fn print_results_part_two(path: &Option<Vec<usize>>, graph: &Graph<String, f32>) {
+
+pub fn format_results_part_two(path: &Option<Vec<Vertex>>) -> Vec<String> {
     match path {
-        Some(v) => {
-            for x in v {
-                let node_name = graph.node_weight(node_index(*x));
-                node_name.map(|node_name| println!("<{}>", node_name));
+        Some(v) => v
+            .iter()
+            .map(|x| format!("<{}, {}>", x.exchange, x.currency))
+            .collect(),
+        None => vec!["There is no path from source to desired destination".to_string()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Engine, DEFAULT_NAMESPACE};
+    use std::sync::{Arc, Mutex};
+
+    /* Collects what an Output writes so a test can look at it.
+     */
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    fn text_output(engine: &mut Engine, lines: &[&str]) -> String {
+        let captured = Captured::default();
+        let mut output = Output::new(OutputFormat::Text, Box::new(captured.clone()));
+        for line in lines {
+            let message = parse_line(line).unwrap().unwrap();
+            if let Some(response) = engine.handle_message(DEFAULT_NAMESPACE, message).unwrap() {
+                output.write_response(&response).unwrap();
             }
         }
-        None => println!("There is no path from source to desired destination"),
+        let bytes = captured.0.lock().unwrap().clone();
+        return String::from_utf8(bytes).unwrap();
+    }
+
+    #[test]
+    fn best_rates_header_ends_with_the_bracketed_version() {
+        let mut engine = Engine::new();
+        let text = text_output(
+            &mut engine,
+            &[
+                "2017-11-01T09:42:23+00:00 KRAKEN BTC USD 1000.0 0.0009",
+                "EXCHANGE_RATE_REQUEST KRAKEN BTC KRAKEN USD",
+                "2017-11-01T09:42:24+00:00 KRAKEN BTC USD 1001.0 0.0009",
+                "EXCHANGE_RATE_REQUEST KRAKEN USD KRAKEN BTC",
+            ],
+        );
+        assert_eq!(
+            text,
+            "BEST_RATES_BEGIN <KRAKEN> <BTC> <KRAKEN> <USD> <1000.0> <1>\n\
+             <KRAKEN, BTC>\n\
+             <KRAKEN, USD>\n\
+             BEST_RATES_END\n\
+             BEST_RATES_BEGIN <KRAKEN> <USD> <KRAKEN> <BTC> <0.0009> <2>\n\
+             <KRAKEN, USD>\n\
+             <KRAKEN, BTC>\n\
+             BEST_RATES_END\n"
+        );
+    }
+
+    #[test]
+    fn a_request_can_name_its_namespace_in_a_sixth_token() {
+        let namespace = |line: &str| match parse_line(line) {
+            Ok(Some(Message::ExchangeRateRequest(request))) => return Ok(request.namespace),
+            Ok(_) => panic!("expected a request"),
+            Err(e) => return Err(e),
+        };
+        assert_eq!(
+            namespace("EXCHANGE_RATE_REQUEST KRAKEN BTC KRAKEN USD"),
+            Ok(None)
+        );
+        assert_eq!(
+            namespace("EXCHANGE_RATE_REQUEST KRAKEN BTC KRAKEN USD Sim"),
+            Ok(Some("Sim".to_string()))
+        );
+        assert!(namespace("EXCHANGE_RATE_REQUEST KRAKEN BTC KRAKEN USD sim extra").is_err());
     }
 }
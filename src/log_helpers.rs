@@ -0,0 +1,63 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/* Runtime log level. This replaces the old compile-time DEBUG const so that diagnostics can be
+ * switched on from the command line without recompiling. All log output goes to stderr so that
+ * stdout only ever carries results.
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(LogLevel::Warn as usize);
+
+pub fn parse_log_level(input: &str) -> Result<LogLevel, String> {
+    match input.to_ascii_lowercase().as_str() {
+        "error" => Ok(LogLevel::Error),
+        "warn" | "warning" => Ok(LogLevel::Warn),
+        "info" => Ok(LogLevel::Info),
+        "debug" => Ok(LogLevel::Debug),
+        _ => Err(format!(
+            "Unknown log level '{}'. Expected one of: error, warn, info, debug",
+            input
+        )),
+    }
+}
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+pub fn log_enabled(level: LogLevel) -> bool {
+    /* Returns true if messages at the given level should be written.
+     * Callers that need to build an expensive message should check this first.
+     */
+    return level as usize <= LOG_LEVEL.load(Ordering::Relaxed);
+}
+
+pub fn log_error(message: &str) {
+    if log_enabled(LogLevel::Error) {
+        eprintln!("ERROR: {}", message);
+    }
+}
+
+pub fn log_warn(message: &str) {
+    if log_enabled(LogLevel::Warn) {
+        eprintln!("WARN: {}", message);
+    }
+}
+
+pub fn log_info(message: &str) {
+    if log_enabled(LogLevel::Info) {
+        eprintln!("INFO: {}", message);
+    }
+}
+
+pub fn log_debug(message: &str) {
+    if log_enabled(LogLevel::Debug) {
+        eprintln!("DEBUG: {}", message);
+    }
+}
@@ -3,31 +3,59 @@
 * (The Exchange Rate Path Problem)
 */
 
+// Explicit returns are the house style throughout this crate.
+#![allow(clippy::needless_return)]
+
 extern crate chrono;
-extern crate matrix;
 extern crate petgraph;
-extern crate rust_decimal;
 
-use crate::graph_helpers::{
-    get_index_from_node, graph_contains, process_edges_between_two_nodes,
-    process_edges_same_currency, vertex_string_format,
+use crate::backtest_helpers::{BacktestOptions, DEFAULT_BACKTEST_SIZE, DEFAULT_MAX_HOPS};
+use crate::bench_helpers::BenchKind;
+use crate::cli_helpers::{parse_args, Cli, Command, USAGE};
+use crate::config_helpers::{load_config, Config};
+use crate::engine::{Costs, Engine, SharedEngine, DEFAULT_NAMESPACE, DEFAULT_STALE_AFTER_SECS};
+use crate::io_helpers::{
+    open_input, open_output, output_format_name, parse_output_format, LineParser, Output,
+    OutputFormat, Response,
 };
-use crate::io_helpers::{print_results_part_one, print_results_part_two};
-use crate::modified_floyd_warshall_helpers::{
-    get_best_rates, get_path_from_request, make_best_rate_table, make_next_table,
-    modified_floyd_warshall,
+use crate::log_helpers::{log_error, log_info, log_warn, parse_log_level, set_log_level, LogLevel};
+use crate::matrix_helpers::{numeric_mode_name, parse_numeric_mode, NumericMode};
+use crate::metrics_helpers::{serve_metrics, Metrics};
+use crate::pipeline_helpers::{
+    apply_update_batch, backpressure_name, parse_backpressure, spawn_sink, spawn_source,
+    Backpressure, BoundedQueue, Popped, DEFAULT_COALESCE_WINDOW, DEFAULT_QUEUE_CAPACITY,
 };
+use crate::replay_helpers::{parse_replay_speed, replay_speed_name, ReplaySpeed};
+use crate::symbol_helpers::{current_symbols, parse_symbol_case, set_symbols, SymbolCase, Symbols};
 
 use chrono::{DateTime, FixedOffset};
-use petgraph::Graph;
-use std::io;
-use std::io::{BufRead, BufReader};
+use serde::Deserialize;
+use std::env;
+use std::io::BufRead;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
 
+mod backtest_helpers;
+mod bench_helpers;
+mod cli_helpers;
+mod config_helpers;
 mod datetime_helpers;
+mod engine;
+mod export_helpers;
 mod graph_helpers;
 mod io_helpers;
+mod log_helpers;
+mod matrix_helpers;
+mod metrics_helpers;
 mod modified_floyd_warshall_helpers;
+mod pipeline_helpers;
+mod replay_helpers;
+mod server_helpers;
+mod symbol_helpers;
 
+#[derive(Clone, Debug)]
 pub struct PriceUpdate {
     timestamp: DateTime<FixedOffset>,
     exchange: String,
@@ -37,198 +65,624 @@ pub struct PriceUpdate {
     backward_factor: f32,
 }
 
-#[derive(Clone, Eq, PartialEq)]
+/* A delisting: the exchange no longer trades between the two currencies as of the timestamp.
+ */
+#[derive(Clone, Debug)]
+pub struct PairRemoval {
+    timestamp: DateTime<FixedOffset>,
+    exchange: String,
+    source_currency: String,
+    destination_currency: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Vertex {
     exchange: String,
     currency: String,
 }
 
+/* Trade edges come from price updates. Transfer edges join vertices that share a currency,
+ * i.e. moving a currency between exchanges.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EdgeKind {
+    Trade,
+    Transfer,
+}
+
+/* Everything known about an edge, kept as its petgraph edge weight so it moves with the edge
+ * whenever petgraph renumbers edges. exchange is the exchange of the source vertex: the one
+ * quoting a trade, or the one a transfer moves the currency out of.
+ */
 #[derive(Clone, PartialEq, Debug)]
 pub struct Edge {
-    source_index: usize,
-    dest_index: usize,
     rate: f32,
     timestamp: DateTime<FixedOffset>,
+    kind: EdgeKind,
+    exchange: String,
 }
-#[derive(Clone, Eq, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
 pub struct ExchangeRateRequest {
     source_exchange: String,
     source_currency: String,
     destination_exchange: String,
     destination_currency: String,
+    #[serde(default)]
+    namespace: Option<String>,
+}
+/* A parsed input line.
+ */
+pub enum Message {
+    PriceUpdate(PriceUpdate),
+    ExchangeRateRequest(ExchangeRateRequest),
+    ListExchanges,
+    ListCurrencies,
+    ListEdges(Option<String>),
+    Spreads(Option<String>),
+    BestVenue(String, String),
+    ShowEdge(Vertex, Vertex),
+    Stats,
+    ExportDot(String),
+    ExportMatrix(String),
+    ExportSpreads(String),
+    Metrics,
+    Batch(Vec<ExchangeRateRequest>),
+    PairRemove(PairRemoval),
+    VertexRemove(Vertex),
+    ExchangeHalt(String),
+    ExchangeResume(String),
+    ConfigShow,
+    ConfigReload,
+    Use(String),
+    ListNamespaces,
+    CheckConsistency,
 }
+
 const REQUEST_PARAMETERS: usize = 5;
 const UPDATE_PARAMETERS: usize = 6;
 const REQUEST_HEADER: &str = "EXCHANGE_RATE_REQUEST";
 const DEFAULT_EDGE_WEIGHT: f32 = 1.0;
-const DEBUG: bool = false;
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:7878";
+// How often a thread waiting for input looks to see whether a SIGHUP has arrived.
+const SIGHUP_POLL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/* The effective settings after merging the configuration file with the command line.
+ */
+struct Settings {
+    inputs: Vec<String>,
+    output: Option<String>,
+    format: OutputFormat,
+    quiet: bool,
+    listen: String,
+    metrics_listen: Option<String>,
+    stale_after_secs: Option<i64>,
+    threads: Option<usize>,
+    numeric: NumericMode,
+    verify: bool,
+    detailed: bool,
+    backpressure: Backpressure,
+    queue_capacity: usize,
+    coalesce_window: usize,
+    replay_speed: ReplaySpeed,
+    backtest: BacktestOptions,
+    log_level: LogLevel,
+    symbols: Symbols,
+    costs: Costs,
+    config: Config,
+}
 
 fn main() {
     /* =================================== Start of main function ==================================
-     * Running this allows a user to input price updates or exchange rate requests via stdin.
-     * The function will take the price updates and build a graph which will be used by the
-     * floyd-warshall algorithm to ultimately display to stdout the best exchange rate available
+     * Running this allows a user to input price updates or exchange rate requests via stdin,
+     * input files or a TCP socket. Price updates are used to build a graph which will be used by
+     * the floyd-warshall algorithm to ultimately display the best exchange rate available
      * at the time as well as the trades required to achieve this rate.
      * More information can be found in the readme.
      */
-    let mut graph = Graph::<String, f32>::new();
-    let mut edge_data: Vec<Edge> = Vec::new();
-
-    println!("Please enter either a Price Update or an Exchange Rate Request: ");
-    let stdin = io::stdin();
-    let reader = BufReader::new(stdin);
-    let lines = reader.lines();
-
-    for line in lines {
-        let input_string = match &line {
-            Ok(s) => s,
-            Err(e) => panic!("Error getting lines from input: {}", e),
-        };
+    let args: Vec<String> = env::args().skip(1).collect();
+    let cli = match parse_args(&args) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if cli.help {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let settings = match load_settings(&cli) {
+        Ok(s) => s,
+        Err(e) => {
+            log_error(&e);
+            process::exit(2);
+        }
+    };
+    apply_globals(&settings);
+
+    let result = match cli.command {
+        Command::Run | Command::Replay => {
+            make_engine(&cli, &settings).and_then(|e| run(&settings, e))
+        }
+        Command::Serve => make_engine(&cli, &settings).and_then(|e| {
+            let engine = Arc::new(SharedEngine::new(e));
+            reload_on_sighup(Arc::clone(&engine))?;
+            server_helpers::serve(&settings.listen, engine, settings.format)
+        }),
+        Command::Check => check(&settings),
+        Command::Backtest => make_engine(&cli, &settings).and_then(|e| backtest(&settings, e)),
+        Command::Bench => {
+            let threads = settings.threads.unwrap_or_else(default_threads);
+            match cli.bench {
+                BenchKind::FloydWarshall => bench_helpers::bench_floyd_warshall(
+                    cli.vertices,
+                    threads,
+                    cli.iterations,
+                    cli.seed,
+                ),
+                BenchKind::Updates => {
+                    bench_helpers::bench_updates(cli.vertices, cli.iterations, cli.seed)
+                }
+            }
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        log_error(&e);
+        process::exit(1);
+    }
+}
+
+fn load_settings(cli: &Cli) -> Result<Settings, String> {
+    /* Loads the configuration file if one was given and lets the command line override it.
+     * The effective settings are also written back into a Config for CONFIG_SHOW.
+     * Nothing is applied here, so a config that fails to load on reload changes nothing.
+     */
+    let mut config = match &cli.config {
+        Some(path) => load_config(path)?,
+        None => Config::default(),
+    };
+
+    let log_level = match (cli.log_level, &config.log_level) {
+        (Some(level), _) => level,
+        (None, Some(level)) => parse_log_level(level)?,
+        (None, None) => LogLevel::Warn,
+    };
+
+    let format = match (cli.format, &config.output.format) {
+        (Some(format), _) => format,
+        (None, Some(format)) => parse_output_format(format)?,
+        (None, None) => OutputFormat::Text,
+    };
+
+    let case = match &config.symbols.case {
+        Some(case) => parse_symbol_case(case)?,
+        None => SymbolCase::Upper,
+    };
+    let symbols =
+        Symbols::with_aliases(case, &config.symbols.exchanges, &config.symbols.currencies)?;
+
+    let backpressure = match (cli.backpressure, &config.pipeline.backpressure) {
+        (Some(policy), _) => policy,
+        (None, Some(policy)) => parse_backpressure(policy)?,
+        (None, None) => Backpressure::Block,
+    };
+
+    let replay_speed = match (cli.speed, &config.replay.speed) {
+        (Some(speed), _) => speed,
+        (None, Some(speed)) => parse_replay_speed(speed)?,
+        (None, None) => ReplaySpeed::Max,
+    };
+
+    // Replay applies every update in order, so two runs of the same file give the same output.
+    let replay = cli.command == Command::Replay;
+    let backpressure = if replay {
+        Backpressure::Block
+    } else {
+        backpressure
+    };
+
+    let numeric = match (cli.numeric, &config.numeric) {
+        (Some(numeric), _) => numeric,
+        (None, Some(numeric)) => parse_numeric_mode(numeric)?,
+        (None, None) => NumericMode::F32,
+    };
+
+    // Fees and transfer costs are keyed by symbol, so they go through the same aliases.
+    let costs = Costs {
+        default_fee: cli.fee.or(config.fees.default).unwrap_or(0.0),
+        fees: config
+            .fees
+            .exchanges
+            .iter()
+            .map(|(exchange, fee)| (symbols.exchange(exchange), *fee))
+            .collect(),
+        default_transfer_cost: config.transfer_costs.default.unwrap_or(0.0),
+        transfer_costs: config
+            .transfer_costs
+            .currencies
+            .iter()
+            .map(|(currency, cost)| (symbols.currency(currency), *cost))
+            .collect(),
+        quote_ttl: config.engine.quote_ttl_secs.map(chrono::Duration::seconds),
+    };
+
+    let settings = Settings {
+        inputs: cli.inputs.clone(),
+        output: cli.output.clone().or_else(|| config.output.file.clone()),
+        format,
+        quiet: cli.quiet || config.output.quiet.unwrap_or(false),
+        listen: cli
+            .listen
+            .clone()
+            .or_else(|| config.server.listen.clone())
+            .unwrap_or_else(|| DEFAULT_LISTEN_ADDRESS.to_string()),
+        metrics_listen: cli
+            .metrics_listen
+            .clone()
+            .or_else(|| config.metrics.listen.clone()),
+        stale_after_secs: config.metrics.stale_after_secs,
+        threads: cli.threads.or(config.engine.threads),
+        numeric,
+        verify: cli.verify || config.output.verify.unwrap_or(false),
+        detailed: cli.detailed || config.output.detailed.unwrap_or(false),
+        backpressure,
+        queue_capacity: cli
+            .queue_capacity
+            .or(config.pipeline.capacity)
+            .unwrap_or(DEFAULT_QUEUE_CAPACITY)
+            .max(1),
+        coalesce_window: cli
+            .coalesce_window
+            .or(config.pipeline.coalesce_window)
+            .filter(|_| !replay)
+            .unwrap_or(if replay { 1 } else { DEFAULT_COALESCE_WINDOW }),
+        replay_speed,
+        backtest: BacktestOptions {
+            size: cli
+                .size
+                .or(config.backtest.size)
+                .unwrap_or(DEFAULT_BACKTEST_SIZE),
+            latency: chrono::Duration::milliseconds(
+                cli.latency_ms.or(config.backtest.latency_ms).unwrap_or(0) as i64,
+            ),
+            max_hops: cli
+                .max_hops
+                .or(config.backtest.max_hops)
+                .unwrap_or(DEFAULT_MAX_HOPS),
+            min_profit: cli.min_profit.or(config.backtest.min_profit).unwrap_or(0.0),
+        },
+        log_level,
+        symbols,
+        costs,
+        config: Config::default(),
+    };
+
+    config.log_level = Some(format!("{:?}", log_level).to_lowercase());
+    config.numeric = Some(numeric_mode_name(numeric).to_string());
+    config.output.format = Some(output_format_name(format).to_string());
+    config.output.file = settings.output.clone();
+    config.output.quiet = Some(settings.quiet);
+    config.output.verify = Some(settings.verify);
+    config.output.detailed = Some(settings.detailed);
+    config.server.listen = Some(settings.listen.clone());
+    config.metrics.listen = settings.metrics_listen.clone();
+    config.engine.threads = Some(settings.threads.unwrap_or_else(default_threads));
+    config.pipeline.backpressure = Some(backpressure_name(backpressure).to_string());
+    config.pipeline.capacity = Some(settings.queue_capacity);
+    config.pipeline.coalesce_window = Some(settings.coalesce_window);
+    config.replay.speed = Some(replay_speed_name(replay_speed));
+    config.backtest.size = Some(settings.backtest.size);
+    config.backtest.latency_ms = Some(settings.backtest.latency.num_milliseconds() as u64);
+    config.backtest.max_hops = Some(settings.backtest.max_hops);
+    config.backtest.min_profit = Some(settings.backtest.min_profit);
+    config.fees.default = Some(settings.costs.default_fee);
+    config.fees.exchanges = settings.costs.fees.clone();
+    config.transfer_costs.default = Some(settings.costs.default_transfer_cost);
+    config.transfer_costs.currencies = settings.costs.transfer_costs.clone();
+    config.symbols.case = Some(format!("{:?}", case).to_lowercase());
+    return Ok(Settings { config, ..settings });
+}
+
+fn apply_globals(settings: &Settings) {
+    /* The log level and symbol aliases are read all over the crate rather than through the
+     * engine, so they live in globals.
+     */
+    set_log_level(settings.log_level);
+    set_symbols(settings.symbols.clone());
+}
+
+fn make_engine(cli: &Cli, settings: &Settings) -> Result<Engine, String> {
+    /* Creates the engine and starts the metrics endpoint if one was asked for.
+     * With a configuration file the engine can also reload it on CONFIG_RELOAD or SIGHUP.
+     * The listen addresses and output only take effect on restart.
+     */
+    let mut engine = Engine::new();
+    configure_engine(&mut engine, settings);
+    if cli.config.is_some() {
+        let cli = cli.clone();
+        engine.set_reloader(Arc::new(move |engine: &mut Engine| {
+            let settings = load_settings(&cli)?;
+            let symbols_changed = settings.symbols != current_symbols();
+            apply_globals(&settings);
+            configure_engine(engine, &settings);
+            if symbols_changed {
+                engine.canonicalise_symbols();
+            }
+            return Ok(());
+        }));
+    }
+    if let Some(listen) = &settings.metrics_listen {
+        serve_metrics(listen, engine.metrics())?;
+    }
+    return Ok(engine);
+}
+
+fn configure_engine(engine: &mut Engine, settings: &Settings) {
+    /* Also used on reload, so settings that were removed from the file go back to defaults.
+     */
+    let stale_after_secs = settings
+        .stale_after_secs
+        .unwrap_or(DEFAULT_STALE_AFTER_SECS);
+    engine.set_stale_after(chrono::Duration::seconds(stale_after_secs));
+    engine.set_threads(settings.threads.unwrap_or_else(default_threads));
+    engine.set_numeric_mode(settings.numeric);
+    engine.set_verify(settings.verify);
+    engine.set_detailed(settings.detailed);
+    engine.set_costs(settings.costs.clone());
+    engine.set_config(settings.config.clone());
+}
+
+fn reload_config(engine: &mut Engine) {
+    match engine.reload_config() {
+        Ok(()) => log_info("Reloaded the configuration file"),
+        Err(e) => log_error(&format!("Keeping the old configuration: {}", e)),
+    }
+}
+
+#[cfg(unix)]
+fn sighup_flag() -> Result<Arc<AtomicBool>, String> {
+    /* Set when a SIGHUP arrives. run checks it between lines and while waiting for one.
+     */
+    let flag = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&flag))
+        .map_err(|e| format!("Could not listen for SIGHUP: {}", e))?;
+    return Ok(flag);
+}
+
+#[cfg(not(unix))]
+fn sighup_flag() -> Result<Arc<AtomicBool>, String> {
+    return Ok(Arc::new(AtomicBool::new(false)));
+}
+
+fn reload_on_sighup(engine: Arc<SharedEngine>) -> Result<(), String> {
+    /* The server is blocked accepting connections, so a background thread watches for SIGHUP
+     * and reloads the configuration of the shared engine.
+     */
+    let flag = sighup_flag()?;
+    thread::spawn(move || loop {
+        thread::sleep(SIGHUP_POLL);
+        if flag.swap(false, Ordering::Relaxed) {
+            if let Ok(mut engine) = engine.writer() {
+                reload_config(&mut engine);
+            }
+        }
+    });
+    return Ok(());
+}
+
+fn default_threads() -> usize {
+    return std::thread::available_parallelism().map_or(1, |n| n.get());
+}
+
+fn input_paths(settings: &Settings) -> Vec<String> {
+    if settings.inputs.is_empty() {
+        return vec!["-".to_string()];
+    }
+    return settings.inputs.clone();
+}
 
-        match &input_string.split_whitespace().count() {
-            // Proceed only if the input matches the number of expected parameters.
-            &REQUEST_PARAMETERS | &UPDATE_PARAMETERS => {
-                // Check if incoming line is a Request or a Price Update
-
-                let is_request = input_string.contains(REQUEST_HEADER);
-
-                if is_request {
-                    /* If incoming line is a request, Nothing new to add
-                     *  Proceed to build rate/next tables and get best path.
-                     */
-
-                    if graph.node_count() > 0 {
-                        /* Initialise best rate and next tables as defined in the challenge brief.
-                         * I've used helper functions for this to keep the main function clean.
-                         */
-                        let rate = make_best_rate_table(&graph);
-                        let next = make_next_table(&graph);
-
-                        //============== MODIFIED FLOYD-WARSHALL ======================
-                        /* Run the algorithm to get the best rates and next tables for our graph
-                         * and update both lookup tables with the results.
-                         */
-                        let res = modified_floyd_warshall(&rate, &next, &graph);
-                        let rate = res.0;
-                        let next = res.1;
-
-                        /* ========== Process Request ==============
-                         *  Parse the request into a struct for clarity.
-                         *  Send the rate_request to our helper function get_best_rates
-                         *  which willl ookup and return the best possible rate between
-                         *  the desired source and destination.
-                         *
-                         *  We then send the rate_request to get_path_from_request to get the
-                         *  path required to achieve our best rate.
-                         *
-                         *  The results are displayed to the user by
-                         *  using the print_results functions.
-                         */
-                        let rate_request =
-                            io_helpers::exchange_rate_request(input_string.split_whitespace());
-
-                        let best_rate = get_best_rates(rate_request.clone(), &rate, &graph);
-
-                        best_rate
-                            .map(|best_rate| print_results_part_one(&rate_request, &best_rate));
-
-                        let path = get_path_from_request(&rate_request, &next, &graph);
-                        print_results_part_two(&path, &graph);
-
-                        println!("BEST_RATES_END");
-                    }
-                } else {
-                    // Input was found to be a Price Update not a Exchange Rate Request.
-                    let incoming_price_update =
-                        io_helpers::price_update(input_string.split_whitespace());
-
-                    /* ======================= Adding Vertices ===============================
-                     * Check if source and/or destination vertices already exist in our graph
-                     * and only add one or both do not.
-                     */
-                    let vertex_source = Vertex {
-                        exchange: incoming_price_update.exchange.clone(),
-                        currency: incoming_price_update.source_currency.clone(),
-                    };
-                    let vertex_destination = Vertex {
-                        exchange: incoming_price_update.exchange.clone(),
-                        currency: incoming_price_update.destination_currency.clone(),
-                    };
-
-                    if let false = graph_contains(&vertex_source, &graph) {
-                        let node_str = vertex_string_format(&vertex_source);
-                        graph.add_node(node_str);
-                    }
-                    // Similarly for the destination vertex.
-                    if let false = graph_contains(&vertex_destination, &graph) {
-                        let node_str = vertex_string_format(&vertex_destination);
-                        graph.add_node(node_str);
-                    }
-
-                    // ====================== Adding edges ======================
-                    /* The following handles edge creation between vertexes that share
-                     * the same currency but different exchanges.
-                     *
-                     *  Check if vertex_destination can connect to other exchanges
-                     *  that support that currency. Ignores if edge between those already exists.
-                     *  adds a new edge of weight between them if it does not exist.
-                     *  This includes an edge to itself with a weight of 1.0.
-                     */
-                    let res = process_edges_same_currency(
-                        &vertex_destination,
-                        &edge_data,
-                        &incoming_price_update,
-                        &graph,
-                    );
-
-                    // Update graph and edge_data with our results.
-                    graph = res.0;
-                    edge_data = res.1;
-
-                    /* Do the same for vertex_source's currency.
-                     */
-                    let res = process_edges_same_currency(
-                        &vertex_source,
-                        &edge_data,
-                        &incoming_price_update,
-                        &graph,
-                    );
-
-                    // Update graph and edge_data with our results.
-                    graph = res.0;
-                    edge_data = res.1;
-
-                    /* The following adds edges as specified in the incoming price update.
-                     * It only adds edges if they are either found not to exist or if the
-                     * incoming price update is more recent than the existing rate.
-                     * This is done through the function process_edges_between_two_nodes.
-                     */
-                    let source_node_index = get_index_from_node(&vertex_source, &graph);
-                    let dest_node_index = get_index_from_node(&vertex_destination, &graph);
-                    let res = source_node_index.and_then(|source_node_index| {
-                        dest_node_index.map(|dest_node_index| {
-                            process_edges_between_two_nodes(
-                                source_node_index,
-                                dest_node_index,
-                                &incoming_price_update,
-                                &edge_data,
-                                &graph,
-                            )
-                        })
-                    });
-
-                    match res {
-                        Some(r) => {
-                            graph = r.0;
-                            edge_data = r.1;
-                        }
-                        None => println!("There was a problem adding edges between nodes."),
-                    }
+fn run(settings: &Settings, mut engine: Engine) -> Result<(), String> {
+    /* Feeds every input line through the engine in order and writes the responses.
+     * Reading and parsing, the engine and writing the results each run on their own thread,
+     * joined by bounded queues, so a slow Floyd-Warshall doesn't hold up reading the feed.
+     * Lines that can't be parsed or applied are reported on stderr and skipped.
+     * A SIGHUP reloads the configuration before the next message is handled, or, if the input
+     * is idle, within SIGHUP_POLL.
+     * A namespace picked with USE stays in use across the remaining input files.
+     */
+    let output = Output::new(settings.format, open_output(&settings.output)?);
+    let sighup = sighup_flag()?;
+    let metrics = engine.metrics();
+
+    let queue = Arc::new(BoundedQueue::new(
+        settings.queue_capacity,
+        settings.backpressure,
+        Arc::clone(&metrics),
+    ));
+    let source = spawn_source(
+        input_paths(settings),
+        settings.quiet,
+        settings.replay_speed,
+        Arc::clone(&queue),
+        Arc::clone(&metrics),
+    );
+    let (responses, received) = mpsc::sync_channel(settings.queue_capacity);
+    let sink = spawn_sink(output, received);
+
+    let mut namespace = DEFAULT_NAMESPACE.to_string();
+    let mut finished = true;
+    loop {
+        let popped = queue.pop_within(SIGHUP_POLL);
+        if sighup.swap(false, Ordering::Relaxed) {
+            reload_config(&mut engine);
+        }
+        let envelope = match popped {
+            Popped::Message(envelope) => envelope,
+            Popped::TimedOut => continue,
+            Popped::Closed => break,
+        };
+        // Price updates waiting behind this one are coalesced and applied with it.
+        if matches!(envelope.message, Message::PriceUpdate(_)) && settings.coalesce_window > 1 {
+            let mut batch = vec![envelope];
+            batch.extend(queue.pop_updates(settings.coalesce_window - 1));
+            apply_update_batch(&mut engine, &namespace, batch);
+            continue;
+        }
+        let result = engine.handle_message(&namespace, envelope.message);
+        if let Ok(Some(Response::Using(name))) = &result {
+            namespace = name.clone();
+        }
+        match result {
+            Ok(Some(response)) => {
+                if responses.send(response).is_err() {
+                    // The sink stopped, e.g. the reader of `| head` went away.
+                    finished = false;
+                    break;
                 }
             }
-            _ => {
-                eprintln!("Inputs must follow the form of a Price Update or Exchange Rate Request")
+            Ok(None) => {}
+            Err(e) => {
+                Metrics::inc(&metrics.rejected_lines);
+                log_warn(&format!("{}:{}: {}", envelope.path, envelope.line, e));
             }
         }
     }
+    queue.close();
+    drop(responses);
+
+    let sunk = sink
+        .join()
+        .unwrap_or_else(|_| Err("The output thread panicked".to_string()));
+    if !finished {
+        // The source may be waiting on stdin, so it is left to end with the process.
+        return sunk;
+    }
+    let sourced = source
+        .join()
+        .unwrap_or_else(|_| Err("The input thread panicked".to_string()));
+    return sunk.and(sourced);
+}
+
+fn backtest(settings: &Settings, mut engine: Engine) -> Result<(), String> {
+    /* Simulates trading the arbitrage cycles in the recorded files and writes the report.
+     */
+    let report = backtest_helpers::backtest(&settings.inputs, &mut engine, &settings.backtest)?;
+    let mut output = Output::new(settings.format, open_output(&settings.output)?);
+    match output.write_response(&Response::Backtest(report)) {
+        Ok(()) => return Ok(()),
+        Err(ref e) if e.kind() == std::io::ErrorKind::BrokenPipe => return Ok(()),
+        Err(e) => return Err(format!("Error writing results: {}", e)),
+    }
+}
+
+fn check(settings: &Settings) -> Result<(), String> {
+    /* Parses every input line without touching the engine and reports the lines that are
+     * malformed. Exits with an error if any line failed to parse.
+     */
+    let mut updates = 0;
+    let mut requests = 0;
+    let mut commands = 0;
+    let mut errors = 0;
+
+    for path in input_paths(settings) {
+        let reader = open_input(&path)?;
+        let mut parser = LineParser::new();
+        let mut line_count = 0;
+        for (line_number, line) in reader.lines().enumerate() {
+            let input_string = line.map_err(|e| format!("Error reading {}: {}", path, e))?;
+            line_count = line_number + 1;
+            match parser.parse(&input_string) {
+                Ok(Some(Message::PriceUpdate(_))) => updates += 1,
+                Ok(Some(Message::ExchangeRateRequest(_))) => requests += 1,
+                Ok(Some(Message::Batch(batch))) => requests += batch.len(),
+                Ok(Some(_)) => commands += 1,
+                Ok(None) => {}
+                Err(e) => {
+                    errors += 1;
+                    eprintln!("{}:{}: {}", path, line_number + 1, e);
+                }
+            }
+        }
+        if let Err(e) = parser.finish() {
+            errors += 1;
+            eprintln!("{}:{}: {}", path, line_count, e);
+        }
+    }
+
+    if !settings.quiet {
+        println!(
+            "{} price updates, {} exchange rate requests, {} commands, {} errors",
+            updates, requests, commands, errors
+        );
+    }
+    if errors > 0 {
+        return Err(format!("{} invalid lines", errors));
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io_helpers::parse_line;
+    use std::fs;
+
+    fn send(engine: &mut Engine, line: &str) -> Result<Option<Response>, String> {
+        let message = parse_line(line)?.expect("a message");
+        return engine.handle_message(DEFAULT_NAMESPACE, message);
+    }
+
+    fn rate(engine: &mut Engine) -> f32 {
+        match send(engine, "EXCHANGE_RATE_REQUEST KRAKEN BTC KRAKEN USD") {
+            Ok(Some(Response::BestRate(response))) => return response.rate,
+            _ => panic!("expected a rate"),
+        }
+    }
+
+    fn default_fee(engine: &mut Engine) -> Option<f64> {
+        match send(engine, "CONFIG_SHOW") {
+            Ok(Some(Response::Config(config))) => return config.fees.default,
+            _ => panic!("expected the config"),
+        }
+    }
+
+    #[test]
+    fn config_reload_re_derives_rates_and_keeps_the_old_settings_on_failure() {
+        let path = env::temp_dir().join(format!("tenx_test_reload_{}.toml", process::id()));
+        fs::write(&path, "[fees]\ndefault = 0.0\n").unwrap();
+        let args = ["--config".to_string(), path.to_string_lossy().to_string()];
+        let cli = parse_args(&args).unwrap();
+        let mut engine = make_engine(&cli, &load_settings(&cli).unwrap()).unwrap();
+        send(
+            &mut engine,
+            "2017-11-01T09:42:23+00:00 KRAKEN BTC USD 1000.0 0.0009",
+        )
+        .unwrap();
+        assert_eq!(rate(&mut engine), 1000.0);
+
+        fs::write(&path, "[fees]\ndefault = 0.5\n").unwrap();
+        send(&mut engine, "CONFIG_RELOAD").unwrap();
+        assert_eq!(rate(&mut engine), 500.0);
+        assert_eq!(default_fee(&mut engine), Some(0.5));
+
+        for invalid in &["[fees]\ndefault = 1.5\n", "[fees]\nfee = 0.1\n"] {
+            fs::write(&path, invalid).unwrap();
+            assert!(send(&mut engine, "CONFIG_RELOAD").is_err());
+            assert_eq!(rate(&mut engine), 500.0);
+            assert_eq!(default_fee(&mut engine), Some(0.5));
+        }
+        // A new quote rebuilds the tables, still with the fee from before the failed reloads.
+        send(
+            &mut engine,
+            "2017-11-01T09:42:24+00:00 KRAKEN BTC USD 1002.0 0.0009",
+        )
+        .unwrap();
+        assert_eq!(rate(&mut engine), 501.0);
+
+        fs::remove_file(&path).unwrap();
+        assert!(send(&mut engine, "CONFIG_RELOAD").is_err());
+        assert_eq!(rate(&mut engine), 501.0);
+    }
 }
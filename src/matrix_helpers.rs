@@ -0,0 +1,130 @@
+use std::fmt;
+
+/* A dense row-major matrix used for the all-pairs rate and next tables.
+ * Every cell of those tables is filled, so a dense layout is both smaller and faster than a
+ * sparse one: a get is a single index and the O(V3) loop walks memory in order.
+ * An n x n matrix of T always takes n * n * size_of::<T>() bytes.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct DenseMatrix<T> {
+    pub rows: usize,
+    pub columns: usize,
+    data: Vec<T>,
+}
+
+impl<T: Copy> DenseMatrix<T> {
+    pub fn new(rows: usize, columns: usize, fill: T) -> DenseMatrix<T> {
+        return DenseMatrix {
+            rows,
+            columns,
+            data: vec![fill; rows * columns],
+        };
+    }
+
+    pub fn get(&self, (i, j): (usize, usize)) -> T {
+        return self.data[i * self.columns + j];
+    }
+
+    pub fn set(&mut self, (i, j): (usize, usize), value: T) {
+        self.data[i * self.columns + j] = value;
+    }
+
+    pub fn row(&self, i: usize) -> &[T] {
+        return &self.data[i * self.columns..(i + 1) * self.columns];
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        return &mut self.data;
+    }
+
+    pub fn map<U: Copy, F: Fn(T) -> U>(&self, f: F) -> DenseMatrix<U> {
+        return DenseMatrix {
+            rows: self.rows,
+            columns: self.columns,
+            data: self.data.iter().map(|x| f(*x)).collect(),
+        };
+    }
+}
+
+/* How rates are represented while Floyd-Warshall runs.
+ * F32 multiplies rates directly. LogF64 adds natural logs of the rates in f64, which doesn't
+ * underflow or overflow on long paths and keeps more precision.
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NumericMode {
+    F32,
+    LogF64,
+}
+
+pub fn parse_numeric_mode(input: &str) -> Result<NumericMode, String> {
+    match input.to_ascii_lowercase().as_str() {
+        "f32" => Ok(NumericMode::F32),
+        "log-f64" | "log_f64" | "logf64" => Ok(NumericMode::LogF64),
+        _ => Err(format!(
+            "Unknown numeric type '{}'. Expected one of: f32, log-f64",
+            input
+        )),
+    }
+}
+
+pub fn numeric_mode_name(mode: NumericMode) -> &'static str {
+    match mode {
+        NumericMode::F32 => "f32",
+        NumericMode::LogF64 => "log-f64",
+    }
+}
+
+/* A value the modified Floyd-Warshall can work with: something that can be combined along a
+ * path and compared to find the best rate.
+ */
+pub trait RateValue: Copy + Send + Sync + PartialEq + fmt::Display {
+    /* Returns the combined value of going via k (a then b) if it beats the current value u.
+     */
+    fn improved(u: Self, a: Self, b: Self) -> Option<Self>;
+}
+
+impl RateValue for f32 {
+    fn improved(u: f32, a: f32, b: f32) -> Option<f32> {
+        /* The product of two f32s is exact in f64, so the comparison is made before any rounding.
+         */
+        let res = a as f64 * b as f64;
+        if (u as f64) < res {
+            return Some(res as f32);
+        }
+        return None;
+    }
+}
+
+/* The natural log of a rate. An unreachable pair has a rate of 0, i.e. a log of -infinity.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LogRate(pub f64);
+
+impl LogRate {
+    pub fn from_rate(rate: f32) -> LogRate {
+        if rate > 0.0 {
+            return LogRate((rate as f64).ln());
+        }
+        return LogRate(f64::NEG_INFINITY);
+    }
+
+    pub fn to_rate(self) -> f32 {
+        return self.0.exp() as f32;
+    }
+}
+
+impl fmt::Display for LogRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}", self.0);
+    }
+}
+
+impl RateValue for LogRate {
+    fn improved(u: LogRate, a: LogRate, b: LogRate) -> Option<LogRate> {
+        let res = a.0 + b.0;
+        if u.0 < res {
+            return Some(LogRate(res));
+        }
+        return None;
+    }
+}
@@ -0,0 +1,284 @@
+use crate::log_helpers::{log_info, log_warn};
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/* Upper bounds in seconds of the histogram buckets used for timings.
+ */
+const BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/* A Prometheus style histogram made of atomics so it can be updated without a lock.
+ * Durations are accumulated in nanoseconds.
+ */
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (i, bound) in BUCKETS.iter().enumerate() {
+            if seconds <= *bound {
+                self.buckets[i].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (i, bound) in BUCKETS.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                self.buckets[i].load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9
+        );
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/* Counters and gauges describing what the engine has been doing.
+ * One instance is shared between the engine and the /metrics endpoint.
+ */
+#[derive(Default)]
+pub struct Metrics {
+    pub price_updates: AtomicU64,
+    pub requests: AtomicU64,
+    pub requests_failed: AtomicU64,
+    pub rejected_lines: AtomicU64,
+    pub vertices: AtomicU64,
+    pub edges: AtomicU64,
+    pub stale_edges: AtomicU64,
+    pub queue_depth: AtomicU64,
+    pub queue_dropped_updates: AtomicU64,
+    pub queue_coalesced_updates: AtomicU64,
+    pub coalesced_updates: AtomicU64,
+    pub update_batches: AtomicU64,
+    pub floyd_warshall_duration: Histogram,
+    pub request_duration: Histogram,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(counter: &AtomicU64, value: usize) {
+        counter.fetch_add(value as u64, Ordering::Relaxed);
+    }
+
+    pub fn set(gauge: &AtomicU64, value: usize) {
+        gauge.store(value as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        /* Renders every metric in the Prometheus text exposition format.
+         */
+        let mut out = String::new();
+        render_value(
+            &mut out,
+            "tenx_price_updates_total",
+            "counter",
+            "Price updates applied to the graph.",
+            &self.price_updates,
+        );
+        render_value(
+            &mut out,
+            "tenx_requests_total",
+            "counter",
+            "Exchange rate requests handled, including the ones that failed.",
+            &self.requests,
+        );
+        render_value(
+            &mut out,
+            "tenx_requests_failed_total",
+            "counter",
+            "Exchange rate requests that could not be answered.",
+            &self.requests_failed,
+        );
+        render_value(
+            &mut out,
+            "tenx_rejected_lines_total",
+            "counter",
+            "Input lines that could not be parsed or applied.",
+            &self.rejected_lines,
+        );
+        render_value(
+            &mut out,
+            "tenx_graph_vertices",
+            "gauge",
+            "Exchange/currency vertices in the graph.",
+            &self.vertices,
+        );
+        render_value(
+            &mut out,
+            "tenx_graph_edges",
+            "gauge",
+            "Edges in the graph.",
+            &self.edges,
+        );
+        render_value(
+            &mut out,
+            "tenx_stale_edges",
+            "gauge",
+            "Edges whose quote is older than the staleness threshold.",
+            &self.stale_edges,
+        );
+        render_value(
+            &mut out,
+            "tenx_queue_depth",
+            "gauge",
+            "Messages waiting between the input and the engine.",
+            &self.queue_depth,
+        );
+        render_value(
+            &mut out,
+            "tenx_queue_dropped_updates_total",
+            "counter",
+            "Price updates dropped from a full queue.",
+            &self.queue_dropped_updates,
+        );
+        render_value(
+            &mut out,
+            "tenx_queue_coalesced_updates_total",
+            "counter",
+            "Price updates merged with a queued update for the same pair on a full queue.",
+            &self.queue_coalesced_updates,
+        );
+        render_value(
+            &mut out,
+            "tenx_coalesced_updates_total",
+            "counter",
+            "Price updates superseded by a newer update for the same pair in the same batch.",
+            &self.coalesced_updates,
+        );
+        render_value(
+            &mut out,
+            "tenx_update_batches_total",
+            "counter",
+            "Batches of price updates applied to the graph in one go.",
+            &self.update_batches,
+        );
+        self.floyd_warshall_duration.render(
+            &mut out,
+            "tenx_floyd_warshall_duration_seconds",
+            "Time spent building the tables and running Floyd-Warshall.",
+        );
+        self.request_duration.render(
+            &mut out,
+            "tenx_request_duration_seconds",
+            "Time taken to answer an exchange rate request.",
+        );
+        return out;
+    }
+}
+
+fn render_value(out: &mut String, name: &str, kind: &str, help: &str, value: &AtomicU64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+pub fn serve_metrics(listen: &str, metrics: Arc<Metrics>) -> Result<(), String> {
+    /* Starts a background thread answering GET /metrics on the given address.
+     * Each connection gets its own thread, so a client that is slow to send its request
+     * doesn't hold up the others.
+     */
+    let listener = TcpListener::bind(listen)
+        .map_err(|e| format!("Could not listen for metrics on {}: {}", listen, e))?;
+    log_info(&format!("Serving metrics on http://{}/metrics", listen));
+    thread::spawn(move || accept_metrics_requests(listener, metrics));
+    return Ok(());
+}
+
+fn accept_metrics_requests(listener: TcpListener, metrics: Arc<Metrics>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let metrics = Arc::clone(&metrics);
+                thread::spawn(move || {
+                    if let Err(e) = handle_metrics_request(stream, &metrics) {
+                        log_warn(&format!("Error answering metrics request: {}", e));
+                    }
+                });
+            }
+            Err(e) => log_warn(&format!("Failed to accept metrics connection: {}", e)),
+        }
+    }
+}
+
+fn handle_metrics_request(stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Skip the headers, we don't need any of them.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim() != "" {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", "Not Found\n".to_string()),
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    return stream.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn a_stalled_connection_does_not_hold_up_a_scrape() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::default());
+        Metrics::inc(&metrics.requests_failed);
+        thread::spawn(move || accept_metrics_requests(listener, metrics));
+
+        // Connects but never sends a request, so its thread waits out the read timeout.
+        let _stalled = TcpStream::connect(address).unwrap();
+        let mut scrape = TcpStream::connect(address).unwrap();
+        scrape
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        write!(scrape, "GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        scrape.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("tenx_requests_failed_total 1\n"));
+    }
+}
@@ -4,51 +4,219 @@
 */
 
 use crate::graph_helpers::{get_index_from_node, graph_contains};
-use crate::{ExchangeRateRequest, Vertex, DEBUG};
+use crate::io_helpers::PathCheck;
+use crate::log_helpers::{log_enabled, log_warn, LogLevel};
+use crate::{Edge, ExchangeRateRequest, Vertex};
 
-use matrix::format::compressed::Variant;
-use matrix::prelude::Compressed;
-use matrix::Matrix;
+use crate::matrix_helpers::{DenseMatrix, LogRate, RateValue};
 use petgraph::graph::node_index;
 use petgraph::Graph;
-use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
-use rust_decimal::Decimal;
+use std::fmt;
+use std::sync::{Barrier, PoisonError, RwLock};
+use std::thread;
 
-pub fn modified_floyd_warshall(
+/* Graphs with fewer vertices than this are run on a single thread. Below it the cost of
+ * starting the threads and waiting for each other at every k-iteration outweighs the work
+ * each thread gets.
+ */
+pub const PARALLEL_THRESHOLD: usize = 128;
+
+/* Marks a pair in the next table that has no known path. Any real next hop is a vertex index,
+ * which is always less than the number of vertices.
+ */
+pub const NO_NEXT: usize = usize::MAX;
+
+/* How far, relative to the reported rate, the product of the rates along a path may be
+ * before verify_path calls it a mismatch. Each step of Floyd-Warshall rounds to f32.
+ */
+const VERIFY_TOLERANCE: f64 = 1e-5;
+
+/* Why a path couldn't be read out of the next table.
+ */
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PathError {
+    UnknownVertex,
+    NoPath,
+    HopLimitExceeded(usize),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathError::UnknownVertex => {
+                write!(f, "Either Source or Destination does not exist yet.")
+            }
+            PathError::NoPath => write!(f, "There is no path from source to desired destination"),
+            PathError::HopLimitExceeded(hops) => write!(
+                f,
+                "Path reconstruction gave up after {} hops; the next table has a cycle.",
+                hops
+            ),
+        }
+    }
+}
+
+pub fn modified_floyd_warshall<T: RateValue>(
     /* ================================= MODIFIED FLOYD-WARSHALL =================================
      * The modified Floyd-Warshall algorithm used to get the best rate and the path to achieve it.
      * The original algorithm adds edge weights together to get a total however, for our purposes,
      * we are required to multiply them instead to reflect the exchange rates. Furthermore, instead
      * of optimising for the minimum weight, we want to find the maximum rate for the customer.
+     *
+     * The work is done by floyd_warshall_dense. Each k-iteration is split across `threads`.
      */
-    rate: &Compressed<f32>,
-    next: &Compressed<usize>,
-    graph: &Graph<String, f32>,
-) -> (Compressed<f32>, Compressed<usize>) {
+    rate: &DenseMatrix<T>,
+    next: &DenseMatrix<usize>,
+    threads: usize,
+) -> (DenseMatrix<T>, DenseMatrix<usize>) {
     let mut rate_out = rate.clone();
     let mut next_out = next.clone();
+    let n = rate.rows;
+    floyd_warshall_dense(rate_out.as_mut_slice(), next_out.as_mut_slice(), n, threads);
+    return (rate_out, next_out);
+}
+
+pub fn floyd_warshall_dense<T: RateValue>(
+    rate: &mut [T],
+    next: &mut [usize],
+    n: usize,
+    threads: usize,
+) {
+    /* Runs the modified Floyd-Warshall over n x n row-major tables in place.
+     *
+     * The sequential algorithm updates the tables in place, so within a k-iteration rows above k
+     * see row k as it was before the iteration and rows below k see it after row k has been
+     * updated. To give identical results on any number of threads, row k is copied before and
+     * after it is relaxed, and every other row is relaxed against the copy it would have seen
+     * sequentially. Rows only ever write to themselves, so they can be split between threads.
+     *
+     * The threads are started once, each with its own block of rows. At every k the thread
+     * holding row k relaxes it and publishes the copies, then all of them relax their rows
+     * against the copies. A barrier either side keeps them on the same k.
+     */
+    if n == 0 {
+        return;
+    }
+    let mut pivot = Pivot {
+        before: rate[..n].to_vec(),
+        after: rate[..n].to_vec(),
+    };
+    let threads = threads.max(1);
+    if threads == 1 || n <= threads {
+        for k in 0..n {
+            relax_pivot_block(rate, next, 0, n, k, &mut pivot);
+            relax_block(rate, next, 0, n, k, &pivot);
+        }
+        return;
+    }
 
-    for k in 0..graph.node_count() {
-        for i in 0..graph.node_count() {
-            for j in 0..graph.node_count() {
-                let u = Decimal::from_f32(rate_out.get((i, j)));
-                let a = Decimal::from_f32(rate_out.get((i, k)));
-                let b = Decimal::from_f32(rate_out.get((k, j)));
-                //                println!("u: {:?}, a: {:?}, b:  {:?}", u, a, b);
+    let block_rows = n.div_ceil(threads);
+    let pivot = RwLock::new(pivot);
+    let barrier = Barrier::new(n.div_ceil(block_rows));
+    thread::scope(|scope| {
+        let blocks = rate
+            .chunks_mut(block_rows * n)
+            .zip(next.chunks_mut(block_rows * n));
+        for (b, (rate_block, next_block)) in blocks.enumerate() {
+            let (pivot, barrier) = (&pivot, &barrier);
+            scope.spawn(move || {
+                let first = b * block_rows;
+                let owned = first..first + rate_block.len() / n;
+                for k in 0..n {
+                    // Only the thread holding row k writes the copies, the rest go to the barrier.
+                    if owned.contains(&k) {
+                        let mut pivot = pivot.write().unwrap_or_else(PoisonError::into_inner);
+                        relax_pivot_block(rate_block, next_block, first, n, k, &mut pivot);
+                    }
+                    barrier.wait();
+                    {
+                        let pivot = pivot.read().unwrap_or_else(PoisonError::into_inner);
+                        relax_block(rate_block, next_block, first, n, k, &pivot);
+                    }
+                    barrier.wait();
+                }
+            });
+        }
+    });
+}
 
-                let res = a.and_then(|a| b.and_then(|b| a.checked_mul(b)));
+/* Row k as the rows above it and the rows below it see it during a k-iteration.
+ */
+struct Pivot<T> {
+    before: Vec<T>,
+    after: Vec<T>,
+}
 
-                if let Some(true) = u.and_then(|u| res.map(|res| u < res)) {
-                    let x = res.and_then(|res| Decimal::to_f32(&res));
+fn relax_pivot_block<T: RateValue>(
+    /* If row k is in the block of rows starting at row first, relaxes it and copies it into
+     * pivot before and after.
+     */
+    rate: &mut [T],
+    next: &mut [usize],
+    first: usize,
+    n: usize,
+    k: usize,
+    pivot: &mut Pivot<T>,
+) {
+    if k < first || k >= first + rate.len() / n {
+        return;
+    }
+    let row = (k - first) * n..(k - first + 1) * n;
+    pivot.before.copy_from_slice(&rate[row.clone()]);
+    relax_pivot_row(&mut rate[row.clone()], &mut next[row.clone()], k);
+    pivot.after.copy_from_slice(&rate[row]);
+}
 
-                    // Set rate and next.
-                    x.map(|x| rate_out.set((i, j), x));
-                    next_out.set((i, j), next.get((i, k)));
-                };
-            }
+fn relax_block<T: RateValue>(
+    /* Relaxes every row but k in the block of rows starting at row first.
+     */
+    rate: &mut [T],
+    next: &mut [usize],
+    first: usize,
+    n: usize,
+    k: usize,
+    pivot: &Pivot<T>,
+) {
+    let rows = rate.chunks_mut(n).zip(next.chunks_mut(n));
+    for (i, (rate_row, next_row)) in rows.enumerate().map(|(r, row)| (first + r, row)) {
+        if i == k {
+            continue;
+        }
+        let pivot_row = if i < k { &pivot.before } else { &pivot.after };
+        relax_row(rate_row, next_row, pivot_row, k);
+    }
+}
+
+fn relax_row<T: RateValue>(
+    /* Relaxes row i against pivot row k: rate[i][j] = best(rate[i][j], rate[i][k] via rate[k][j]).
+     * rate[i][k] is read as the loop goes since it can change when j == k.
+     */
+    rate_row: &mut [T],
+    next_row: &mut [usize],
+    pivot_row: &[T],
+    k: usize,
+) {
+    for j in 0..rate_row.len() {
+        if let Some(x) = T::improved(rate_row[j], rate_row[k], pivot_row[j]) {
+            rate_row[j] = x;
+            next_row[j] = next_row[k];
+        }
+    }
+}
+
+fn relax_pivot_row<T: RateValue>(
+    /* The same as relax_row for row k itself, where the pivot row is the row being updated.
+     */
+    rate_row: &mut [T],
+    next_row: &mut [usize],
+    k: usize,
+) {
+    for j in 0..rate_row.len() {
+        if let Some(x) = T::improved(rate_row[j], rate_row[k], rate_row[j]) {
+            rate_row[j] = x;
+            next_row[j] = next_row[k];
         }
     }
-    return (rate_out, next_out);
 }
 
 pub fn get_path_from_request(
@@ -59,9 +227,9 @@ pub fn get_path_from_request(
      *  get_path_from_request acts like an adaptor to be called more easily from the main function.
      */
     rate_request: &ExchangeRateRequest,
-    next: &Compressed<usize>,
-    graph: &Graph<String, f32>,
-) -> Option<Vec<usize>> {
+    next: &DenseMatrix<usize>,
+    graph: &Graph<String, Edge>,
+) -> Result<Vec<usize>, PathError> {
     let rate_request = rate_request.clone();
     let source_vertex = Vertex {
         exchange: rate_request.source_exchange,
@@ -72,75 +240,146 @@ pub fn get_path_from_request(
         currency: rate_request.destination_currency,
     };
 
-    let u = get_index_from_node(&source_vertex, &graph);
-    let v = get_index_from_node(&dest_vertex, &graph);
+    let u = get_index_from_node(&source_vertex, graph);
+    let v = get_index_from_node(&dest_vertex, graph);
 
-    let path = u.and_then(|u| v.and_then(|v| get_path_from_index(u, v, next)));
-    return path;
+    match (u, v) {
+        (Some(u), Some(v)) => return get_path_from_index(u, v, next),
+        _ => return Err(PathError::UnknownVertex),
+    }
 }
 
-fn get_path_from_index(u: usize, v: usize, next: &Compressed<usize>) -> Option<Vec<usize>> {
+fn get_path_from_index(
+    u: usize,
+    v: usize,
+    next: &DenseMatrix<usize>,
+) -> Result<Vec<usize>, PathError> {
     /*  Takes source and destination node indices as well as the Next lookup table as input
      *  and returns best path from source to destination if it exists.
+     *  A simple path visits every vertex at most once, so a walk longer than that means the
+     *  table is inconsistent and the walk is abandoned rather than looping forever.
      */
-    let mut path: Vec<usize> = Vec::new();
-    path.push(u);
+    let max_hops = next.rows;
+    let mut path: Vec<usize> = vec![u];
     let mut u = u;
     while u != v {
         u = next.get((u, v));
+        if u == NO_NEXT {
+            return Err(PathError::NoPath);
+        }
         path.push(u);
+        if path.len() > max_hops {
+            return Err(PathError::HopLimitExceeded(max_hops));
+        }
     }
-    if DEBUG {
-        println!("{}, {}", u, v);
-        println!("LEN: {}", path.len());
+    if log_enabled(LogLevel::Debug) {
+        eprintln!("{}, {}", u, v);
+        eprintln!("LEN: {}", path.len());
     }
 
-    return Some(path);
+    return Ok(path);
 }
 
-pub fn make_best_rate_table(graph: &Graph<String, f32>) -> Compressed<f32> {
-    /* Builds Rate lookup table as specified in the brief.
-     *
+pub fn verify_path(
+    path: &[usize],
+    graph: &Graph<String, Edge>,
+    rates: &[Option<f32>],
+    reported_rate: f32,
+) -> PathCheck {
+    /* Multiplies the current edge rates along a path returned by get_path_from_request and
+     * compares the product with the best rate reported for it.
+     * rates holds the rate of every edge as used in the rate table, after fees and costs.
      */
-    let mut rate: Compressed<f32> = Compressed::zero((graph.node_count(), graph.node_count()));
-    for i in 0..graph.node_count() {
-        for j in 0..graph.node_count() {
-            let x = graph.find_edge(node_index(i), node_index(j));
-            match x {
-                None => {
-                    rate.set((i, j), 0.0);
-                }
-                Some(e) => {
-                    let y = graph.edge_weight(e);
-                    y.map(|y| rate.set((i, j), *y));
-                }
+    let mut path_rate: f64 = 1.0;
+    for hop in path.windows(2) {
+        let rate = graph
+            .find_edge(node_index(hop[0]), node_index(hop[1]))
+            .and_then(|e| rates.get(e.index()).copied().flatten());
+        match rate {
+            Some(rate) => path_rate *= rate as f64,
+            None => {
+                path_rate = 0.0;
+                break;
             }
         }
     }
+    let reported = reported_rate as f64;
+    let matches = (path_rate - reported).abs() <= VERIFY_TOLERANCE * reported.abs().max(1e-30);
+    if !matches {
+        log_warn(&format!(
+            "Reported rate {:?} does not match the rate along its path {:?}",
+            reported_rate, path_rate as f32
+        ));
+    }
+    return PathCheck {
+        path_rate: path_rate as f32,
+        matches,
+    };
+}
+
+pub fn make_best_rate_table(
+    graph: &Graph<String, Edge>,
+    rates: &[Option<f32>],
+) -> DenseMatrix<f32> {
+    /* Builds Rate lookup table as specified in the brief.
+     * rates holds the rate to use for each edge, indexed like the graph's edges. Edges whose
+     * rate is None are left out, as are pairs without an edge; both have a rate of 0.
+     */
+    let n = graph.node_count();
+    let mut rate: DenseMatrix<f32> = DenseMatrix::new(n, n, 0.0);
+    for (i, j, r) in usable_edges(graph, rates) {
+        rate.set((i, j), r);
+    }
 
     // Prints out the initial "rate" lookup table.
-    if DEBUG {
-        display_rate_table(&rate);
+    if log_enabled(LogLevel::Debug) {
+        display_table(&rate);
     }
     return rate;
 }
 
-pub fn make_next_table(graph: &Graph<String, f32>) -> Compressed<usize> {
+pub fn make_log_rate_table(
+    graph: &Graph<String, Edge>,
+    rates: &[Option<f32>],
+) -> DenseMatrix<LogRate> {
+    /* The same as make_best_rate_table with every rate replaced by its natural log,
+     * for running Floyd-Warshall in log space.
+     */
+    return make_best_rate_table(graph, rates).map(LogRate::from_rate);
+}
+
+fn usable_edges<'a>(
+    graph: &'a Graph<String, Edge>,
+    rates: &'a [Option<f32>],
+) -> impl Iterator<Item = (usize, usize, f32)> + 'a {
+    return graph
+        .raw_edges()
+        .iter()
+        .enumerate()
+        .filter_map(move |(e, edge)| {
+            let rate = rates.get(e).copied().flatten()?;
+            Some((edge.source().index(), edge.target().index(), rate))
+        });
+}
+
+pub fn make_next_table(graph: &Graph<String, Edge>, rates: &[Option<f32>]) -> DenseMatrix<usize> {
     /* Creates initial state for the "next" lookup table as specified in the brief.
-     *
+     * next[i][j] is j where there is an edge from i to j and NO_NEXT where there isn't,
+     * so pairs that Floyd-Warshall never connects can be told apart from one-hop paths.
      */
-    let mut next: Compressed<usize> =
-        Compressed::new((graph.node_count(), graph.node_count()), Variant::Column);
+    let n = graph.node_count();
+    let mut next: DenseMatrix<usize> = DenseMatrix::new(n, n, NO_NEXT);
 
-    for i in 0..graph.node_count() {
-        for j in 0..graph.node_count() {
-            next.set((i, j), j);
-        }
+    for i in 0..n {
+        next.set((i, i), i);
+    }
+    for (i, j, _) in usable_edges(graph, rates) {
+        next.set((i, j), j);
     }
 
     // Prints out the initial "next" lookup table
-    if DEBUG {
-        display_next_table(&next);
+    if log_enabled(LogLevel::Debug) {
+        display_table(&next);
     }
 
     return next;
@@ -150,8 +389,8 @@ pub fn get_best_rates(
     /* Returns the best possible rate given the current data.
      */
     rate_request: ExchangeRateRequest,
-    rate: &Compressed<f32>,
-    graph: &Graph<String, f32>,
+    rate: &DenseMatrix<f32>,
+    graph: &Graph<String, Edge>,
 ) -> Option<f32> {
     let source_vertex = Vertex {
         exchange: rate_request.source_exchange,
@@ -162,35 +401,204 @@ pub fn get_best_rates(
         currency: rate_request.destination_currency,
     };
 
-    if graph_contains(&source_vertex, &graph) && graph_contains(&dest_vertex, &graph) {
-        let u = get_index_from_node(&source_vertex, &graph);
-        let v = get_index_from_node(&dest_vertex, &graph);
+    if graph_contains(&source_vertex, graph) && graph_contains(&dest_vertex, graph) {
+        let u = get_index_from_node(&source_vertex, graph);
+        let v = get_index_from_node(&dest_vertex, graph);
 
         let best_rate = u.and_then(|u| v.map(|v| rate.get((u, v))));
         return best_rate;
-    } else {
-        println!("Either Source or Destination does not exist yet.");
-        return None;
     }
+    return None;
 }
 
-/* The following two functions were written to display the matrix in a more intuitive manner.
- * Useful for debugging.
+/* The following function was written to display the matrix in a more intuitive manner.
+ * Useful for debugging. Written to stderr so it doesn't mix with results.
  */
-fn display_rate_table(matrix: &Compressed<f32>) {
+fn display_table<T: Copy + fmt::Display>(matrix: &DenseMatrix<T>) {
     for i in 0..matrix.rows {
         for j in 0..matrix.columns {
-            print!("{} ", matrix.get((i, j)));
+            eprint!("{} ", matrix.get((i, j)));
         }
-        print!("\n");
+        eprintln!();
     }
 }
 
-fn display_next_table(matrix: &Compressed<usize>) {
-    for i in 0..matrix.rows {
-        for j in 0..matrix.columns {
-            print!("{} ", matrix.get((i, j)));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench_helpers::random_rate_table;
+    use crate::io_helpers::parse_line;
+    use crate::matrix_helpers::NumericMode;
+    use crate::{EdgeKind, Message};
+    use chrono::DateTime;
+
+    /* X A -> X B -> X C -> X D, with a direct X A -> X C that is worse than going through
+     * X B, and X B -> X A back. X E has no edges.
+     */
+    const KNOWN_EDGES: &[(usize, usize, f32)] = &[
+        (0, 1, 2.0),
+        (1, 2, 3.0),
+        (0, 2, 5.0),
+        (2, 3, 0.5),
+        (1, 0, 0.25),
+    ];
+
+    fn known_graph() -> (Graph<String, Edge>, Vec<Option<f32>>) {
+        let mut graph = Graph::new();
+        for currency in &["A", "B", "C", "D", "E"] {
+            graph.add_node(format!("X, {}", currency));
+        }
+        let timestamp = DateTime::parse_from_rfc3339("2017-11-01T09:42:23+00:00").unwrap();
+        for (u, v, rate) in KNOWN_EDGES {
+            let edge = Edge {
+                rate: *rate,
+                timestamp,
+                kind: EdgeKind::Trade,
+                exchange: "X".to_string(),
+            };
+            graph.add_edge(node_index(*u), node_index(*v), edge);
+        }
+        let rates = KNOWN_EDGES.iter().map(|(_, _, rate)| Some(*rate)).collect();
+        return (graph, rates);
+    }
+
+    fn tables(
+        graph: &Graph<String, Edge>,
+        rates: &[Option<f32>],
+        mode: NumericMode,
+    ) -> (DenseMatrix<f32>, DenseMatrix<usize>) {
+        let next = make_next_table(graph, rates);
+        match mode {
+            NumericMode::F32 => {
+                return modified_floyd_warshall(&make_best_rate_table(graph, rates), &next, 1);
+            }
+            NumericMode::LogF64 => {
+                let rate = make_log_rate_table(graph, rates);
+                let (rate, next) = modified_floyd_warshall(&rate, &next, 1);
+                return (rate.map(LogRate::to_rate), next);
+            }
+        }
+    }
+
+    fn run<T: RateValue>(
+        rate: &[T],
+        next: &[usize],
+        n: usize,
+        threads: usize,
+    ) -> (Vec<T>, Vec<usize>) {
+        let (mut rate, mut next) = (rate.to_vec(), next.to_vec());
+        floyd_warshall_dense(&mut rate, &mut next, n, threads);
+        return (rate, next);
+    }
+
+    #[test]
+    fn parallel_matches_sequential_above_the_threshold() {
+        let n = PARALLEL_THRESHOLD + 37;
+        let (rate, next) = random_rate_table(n, 0.05, 7);
+        let sequential = run(&rate, &next, n, 1);
+        for threads in &[2, 3, 8] {
+            assert!(
+                run(&rate, &next, n, *threads) == sequential,
+                "{} threads",
+                threads
+            );
+        }
+
+        let log_rate: Vec<LogRate> = rate.iter().map(|r| LogRate::from_rate(*r)).collect();
+        let sequential = run(&log_rate, &next, n, 1);
+        assert!(run(&log_rate, &next, n, 4) == sequential);
+    }
+
+    fn request(line: &str) -> ExchangeRateRequest {
+        match parse_line(line) {
+            Ok(Some(Message::ExchangeRateRequest(request))) => return request,
+            _ => panic!("expected a request"),
         }
-        print!("\n");
+    }
+
+    #[test]
+    fn known_graph_gives_the_expected_rates_and_next_hops() {
+        let (graph, rates) = known_graph();
+        // (source, destination, rate, next hop)
+        let expected: &[(usize, usize, f32, usize)] = &[
+            (0, 1, 2.0, 1),
+            (0, 2, 6.0, 1),
+            (0, 3, 3.0, 1),
+            (1, 2, 3.0, 2),
+            (1, 3, 1.5, 2),
+            (2, 3, 0.5, 3),
+            (1, 0, 0.25, 0),
+            (2, 0, 0.0, NO_NEXT),
+            (3, 0, 0.0, NO_NEXT),
+            (0, 4, 0.0, NO_NEXT),
+        ];
+        for mode in &[NumericMode::F32, NumericMode::LogF64] {
+            let (rate, next) = tables(&graph, &rates, *mode);
+            for (u, v, expected_rate, hop) in expected {
+                let got = rate.get((*u, *v));
+                assert!(
+                    (got - expected_rate).abs() <= 1e-6 * expected_rate,
+                    "{:?} rate {} -> {} is {}",
+                    mode,
+                    u,
+                    v,
+                    got
+                );
+                assert_eq!(next.get((*u, *v)), *hop, "{:?} next {} -> {}", mode, u, v);
+            }
+        }
+    }
+
+    #[test]
+    fn an_unreachable_destination_has_no_path() {
+        let (graph, rates) = known_graph();
+        let (_, next) = tables(&graph, &rates, NumericMode::F32);
+        let path = |line| get_path_from_request(&request(line), &next, &graph);
+        assert_eq!(path("EXCHANGE_RATE_REQUEST X A X D"), Ok(vec![0, 1, 2, 3]));
+        assert_eq!(
+            path("EXCHANGE_RATE_REQUEST X D X A"),
+            Err(PathError::NoPath)
+        );
+        assert_eq!(
+            path("EXCHANGE_RATE_REQUEST X A X E"),
+            Err(PathError::NoPath)
+        );
+        assert_eq!(
+            path("EXCHANGE_RATE_REQUEST X A X F"),
+            Err(PathError::UnknownVertex)
+        );
+    }
+
+    #[test]
+    fn a_cycle_in_the_next_table_hits_the_hop_limit() {
+        let (graph, rates) = known_graph();
+        let (_, mut next) = tables(&graph, &rates, NumericMode::F32);
+        // X B heads back to X A on the way to X D, which heads to X B again.
+        next.set((1, 3), 0);
+        let path = get_path_from_request(&request("EXCHANGE_RATE_REQUEST X A X D"), &next, &graph);
+        assert_eq!(path, Err(PathError::HopLimitExceeded(graph.node_count())));
+    }
+
+    #[test]
+    fn verify_path_reports_a_rate_changed_after_the_tables_were_built() {
+        let (graph, mut rates) = known_graph();
+        let (rate, next) = tables(&graph, &rates, NumericMode::F32);
+        let path = get_path_from_request(&request("EXCHANGE_RATE_REQUEST X A X D"), &next, &graph)
+            .expect("a path");
+        let reported = rate.get((0, 3));
+        let check = verify_path(&path, &graph, &rates, reported);
+        assert!(check.matches);
+        assert_eq!(check.path_rate, 3.0);
+
+        // X B -> X C is the second edge.
+        rates[1] = Some(2.5);
+        let check = verify_path(&path, &graph, &rates, reported);
+        assert!(!check.matches);
+        assert_eq!(check.path_rate, 2.5);
+
+        rates[1] = None;
+        let check = verify_path(&path, &graph, &rates, reported);
+        assert!(!check.matches);
+        assert_eq!(check.path_rate, 0.0);
     }
 }
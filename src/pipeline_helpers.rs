@@ -0,0 +1,563 @@
+use crate::datetime_helpers::is_more_recent;
+use crate::engine::Engine;
+use crate::io_helpers::{open_input, LineParser, Output, Response};
+use crate::log_helpers::log_warn;
+use crate::metrics_helpers::Metrics;
+use crate::replay_helpers::{Pacer, ReplaySpeed};
+use crate::{Message, PriceUpdate};
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::io::{BufRead, IsTerminal};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+pub const DEFAULT_COALESCE_WINDOW: usize = 256;
+
+/* What a full queue does with another price update.
+ * Block waits for room. DropOldest makes room by dropping the oldest queued price update.
+ * Coalesce replaces a queued update for the same pair on the same exchange, as only the most
+ * recent quote for a pair is kept anyway. It only looks at the updates queued after the last
+ * request or command. Requests and commands are never dropped or merged, they always wait
+ * for room.
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Backpressure {
+    Block,
+    DropOldest,
+    Coalesce,
+}
+
+pub fn parse_backpressure(input: &str) -> Result<Backpressure, String> {
+    match input.to_ascii_lowercase().as_str() {
+        "block" => Ok(Backpressure::Block),
+        "drop-oldest" | "drop_oldest" => Ok(Backpressure::DropOldest),
+        "coalesce" => Ok(Backpressure::Coalesce),
+        _ => Err(format!(
+            "Unknown backpressure policy '{}'. Expected one of: block, drop-oldest, coalesce",
+            input
+        )),
+    }
+}
+
+pub fn backpressure_name(policy: Backpressure) -> &'static str {
+    match policy {
+        Backpressure::Block => "block",
+        Backpressure::DropOldest => "drop-oldest",
+        Backpressure::Coalesce => "coalesce",
+    }
+}
+
+/* What waiting on the queue for a limited time gave back.
+ */
+pub enum Popped {
+    Message(Envelope),
+    TimedOut,
+    Closed,
+}
+
+/* A parsed line along with where it came from, so errors can still be reported against the
+ * file and line number once the engine gets to it.
+ */
+pub struct Envelope {
+    pub path: String,
+    pub line: usize,
+    pub message: Message,
+}
+
+struct QueueState {
+    items: VecDeque<Envelope>,
+    closed: bool,
+}
+
+/* A bounded queue between the source and the engine.
+ */
+pub struct BoundedQueue {
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    policy: Backpressure,
+    metrics: Arc<Metrics>,
+}
+
+impl BoundedQueue {
+    pub fn new(capacity: usize, policy: Backpressure, metrics: Arc<Metrics>) -> BoundedQueue {
+        return BoundedQueue {
+            state: Mutex::new(QueueState {
+                items: VecDeque::new(),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity: capacity.max(1),
+            policy,
+            metrics,
+        };
+    }
+
+    pub fn push(&self, envelope: Envelope) -> Result<(), String> {
+        /* Adds a message, applying the backpressure policy if the queue is full.
+         * Fails once the queue has been closed by the engine.
+         */
+        let mut state = self.lock()?;
+        loop {
+            if state.closed {
+                return Err("The engine has stopped".to_string());
+            }
+            if state.items.len() < self.capacity {
+                break;
+            }
+            if let Message::PriceUpdate(update) = &envelope.message {
+                if self.make_room(&mut state.items, update) {
+                    if state.items.len() < self.capacity {
+                        break;
+                    }
+                    // The incoming update was older than the queued one it would replace.
+                    return Ok(());
+                }
+            }
+            state = self
+                .not_full
+                .wait(state)
+                .map_err(|_| "The queue is unavailable".to_string())?;
+        }
+        state.items.push_back(envelope);
+        Metrics::set(&self.metrics.queue_depth, state.items.len());
+        self.not_empty.notify_one();
+        return Ok(());
+    }
+
+    fn make_room(&self, items: &mut VecDeque<Envelope>, update: &PriceUpdate) -> bool {
+        /* Returns true if the policy dealt with the update without waiting: either room was
+         * made for it, or it was merged into an update that is already queued.
+         */
+        match self.policy {
+            Backpressure::Block => return false,
+            Backpressure::DropOldest => {
+                let oldest = items
+                    .iter()
+                    .position(|e| matches!(e.message, Message::PriceUpdate(_)));
+                match oldest {
+                    Some(i) => {
+                        items.remove(i);
+                        Metrics::inc(&self.metrics.queue_dropped_updates);
+                        return true;
+                    }
+                    None => return false,
+                }
+            }
+            Backpressure::Coalesce => {
+                // Only updates behind the last request or command can be merged, so a request
+                // still sees the updates before it, and a USE can't mix up two namespaces.
+                let start = items
+                    .iter()
+                    .rposition(|e| !matches!(e.message, Message::PriceUpdate(_)))
+                    .map_or(0, |i| i + 1);
+                let queued = items.iter().skip(start).position(|e| match &e.message {
+                    Message::PriceUpdate(queued) => same_pair(queued, update),
+                    _ => false,
+                });
+                let i = match queued {
+                    Some(i) => start + i,
+                    None => return false,
+                };
+                Metrics::inc(&self.metrics.queue_coalesced_updates);
+                // A tie keeps the queued update, which would have been applied first.
+                let newer = match &items[i].message {
+                    Message::PriceUpdate(queued) => {
+                        is_more_recent(update.timestamp, queued.timestamp)
+                    }
+                    _ => true,
+                };
+                if newer {
+                    items.remove(i);
+                }
+                return true;
+            }
+        }
+    }
+
+    pub fn pop_within(&self, timeout: std::time::Duration) -> Popped {
+        /* Waits up to timeout for the next message, so the caller can look at other things,
+         * such as a signal, while the queue is empty. Closed once the queue is closed and empty.
+         */
+        let mut state = match self.lock() {
+            Ok(state) => state,
+            Err(_) => return Popped::Closed,
+        };
+        loop {
+            if let Some(envelope) = state.items.pop_front() {
+                Metrics::set(&self.metrics.queue_depth, state.items.len());
+                self.not_full.notify_one();
+                return Popped::Message(envelope);
+            }
+            if state.closed {
+                return Popped::Closed;
+            }
+            state = match self.not_empty.wait_timeout(state, timeout) {
+                Ok((_, result)) if result.timed_out() => return Popped::TimedOut,
+                Ok((state, _)) => state,
+                Err(_) => return Popped::Closed,
+            };
+        }
+    }
+
+    pub fn pop_updates(&self, max: usize) -> Vec<Envelope> {
+        /* Takes up to max price updates that are already waiting at the front of the queue,
+         * without waiting for more. Stops at the first request or command, so a request
+         * still only sees the updates that came before it.
+         */
+        let mut updates = Vec::new();
+        let mut state = match self.lock() {
+            Ok(state) => state,
+            Err(_) => return updates,
+        };
+        while updates.len() < max {
+            match state.items.front() {
+                Some(Envelope {
+                    message: Message::PriceUpdate(_),
+                    ..
+                }) => {}
+                _ => break,
+            }
+            if let Some(envelope) = state.items.pop_front() {
+                updates.push(envelope);
+            }
+        }
+        Metrics::set(&self.metrics.queue_depth, state.items.len());
+        self.not_full.notify_all();
+        return updates;
+    }
+
+    pub fn close(&self) {
+        /* No more messages will be pushed, or, if the engine closes it, taken.
+         */
+        if let Ok(mut state) = self.state.lock() {
+            state.closed = true;
+        }
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, QueueState>, String> {
+        return self
+            .state
+            .lock()
+            .map_err(|_| "The queue is unavailable".to_string());
+    }
+}
+
+fn same_pair(a: &PriceUpdate, b: &PriceUpdate) -> bool {
+    /* A price update sets both directions of a pair, so BTC/USD and USD/BTC are the same pair.
+     */
+    if a.exchange != b.exchange {
+        return false;
+    }
+    return (a.source_currency == b.source_currency
+        && a.destination_currency == b.destination_currency)
+        || (a.source_currency == b.destination_currency
+            && a.destination_currency == b.source_currency);
+}
+
+fn pair_key(update: &PriceUpdate) -> (String, String, String) {
+    let (a, b) = if update.source_currency <= update.destination_currency {
+        (&update.source_currency, &update.destination_currency)
+    } else {
+        (&update.destination_currency, &update.source_currency)
+    };
+    return (update.exchange.clone(), a.clone(), b.clone());
+}
+
+pub fn coalesce_updates(batch: Vec<Envelope>) -> (Vec<Envelope>, usize) {
+    /* Keeps only the most recent update for each pair on each exchange. An update that is
+     * superseded within the batch would be overwritten straight away, so applying the rest
+     * gives the same graph. Returns the updates to apply and how many were dropped.
+     */
+    let mut kept: Vec<Envelope> = Vec::new();
+    let mut index: BTreeMap<(String, String, String), usize> = BTreeMap::new();
+    let mut coalesced = 0;
+    for envelope in batch {
+        let update = match &envelope.message {
+            Message::PriceUpdate(update) => update,
+            _ => {
+                kept.push(envelope);
+                continue;
+            }
+        };
+        let key = pair_key(update);
+        match index.get(&key) {
+            Some(&i) => {
+                coalesced += 1;
+                // Like applying the updates in turn, a tie keeps the one that came first.
+                let newer = match &kept[i].message {
+                    Message::PriceUpdate(current) => {
+                        is_more_recent(update.timestamp, current.timestamp)
+                    }
+                    _ => true,
+                };
+                if newer {
+                    kept[i] = envelope;
+                }
+            }
+            None => {
+                index.insert(key, kept.len());
+                kept.push(envelope);
+            }
+        }
+    }
+    return (kept, coalesced);
+}
+
+pub fn apply_update_batch(engine: &mut Engine, namespace: &str, batch: Vec<Envelope>) {
+    /* Coalesces a batch of price updates and applies what is left in one go.
+     * Updates that fail are reported against their own line.
+     */
+    let metrics = engine.metrics();
+    let (batch, coalesced) = coalesce_updates(batch);
+    Metrics::add(&metrics.coalesced_updates, coalesced);
+
+    let mut origins = Vec::new();
+    let mut updates = Vec::new();
+    for envelope in batch {
+        if let Message::PriceUpdate(update) = envelope.message {
+            origins.push((envelope.path, envelope.line));
+            updates.push(update);
+        }
+    }
+    let results = match engine.apply_price_updates(namespace, &updates) {
+        Ok(results) => results,
+        Err(e) => vec![Err(e); updates.len()],
+    };
+    for ((path, line), result) in origins.iter().zip(results) {
+        if let Err(e) = result {
+            Metrics::inc(&metrics.rejected_lines);
+            log_warn(&format!("{}:{}: {}", path, line, e));
+        }
+    }
+}
+
+pub fn spawn_source(
+    paths: Vec<String>,
+    quiet: bool,
+    speed: ReplaySpeed,
+    queue: Arc<BoundedQueue>,
+    metrics: Arc<Metrics>,
+) -> thread::JoinHandle<Result<(), String>> {
+    /* Reads and parses every input in order and queues the messages for the engine, paced by
+     * their timestamps unless the speed is Max.
+     * Lines that can't be parsed are reported here and never reach the engine.
+     */
+    return thread::spawn(move || {
+        let result = read_inputs(&paths, quiet, Pacer::new(speed), &queue, &metrics);
+        queue.close();
+        return result;
+    });
+}
+
+fn read_inputs(
+    paths: &[String],
+    quiet: bool,
+    mut pacer: Pacer,
+    queue: &BoundedQueue,
+    metrics: &Metrics,
+) -> Result<(), String> {
+    for path in paths {
+        if path == "-" && !quiet && io::stdin().is_terminal() {
+            eprintln!("Please enter either a Price Update or an Exchange Rate Request: ");
+        }
+        let reader = open_input(path)?;
+        let mut parser = LineParser::new();
+        let mut line_count = 0;
+
+        for (line_number, line) in reader.lines().enumerate() {
+            let input_string = line.map_err(|e| format!("Error reading {}: {}", path, e))?;
+            line_count = line_number + 1;
+            match parser.parse(&input_string) {
+                Ok(Some(message)) => {
+                    pacer.wait(&message);
+                    let envelope = Envelope {
+                        path: path.clone(),
+                        line: line_count,
+                        message,
+                    };
+                    if queue.push(envelope).is_err() {
+                        // The engine stopped, e.g. because the output went away.
+                        return Ok(());
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    Metrics::inc(&metrics.rejected_lines);
+                    log_warn(&format!("{}:{}: {}", path, line_count, e));
+                }
+            }
+        }
+        if let Err(e) = parser.finish() {
+            Metrics::inc(&metrics.rejected_lines);
+            log_warn(&format!("{}:{}: {}", path, line_count, e));
+        }
+    }
+    return Ok(());
+}
+
+pub fn spawn_sink(
+    mut output: Output,
+    responses: Receiver<Response>,
+) -> thread::JoinHandle<Result<(), String>> {
+    /* Writes the engine's responses in the order they were produced. Stops quietly if the
+     * reader goes away, e.g. `| head`, which in turn stops the engine and the source.
+     */
+    return thread::spawn(move || {
+        for response in responses {
+            match output.write_response(&response) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+                Err(e) => return Err(format!("Error writing results: {}", e)),
+            }
+        }
+        return Ok(());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io_helpers::parse_line;
+
+    fn envelope(line: &str) -> Envelope {
+        return Envelope {
+            path: "test".to_string(),
+            line: 1,
+            message: parse_line(line).unwrap().unwrap(),
+        };
+    }
+
+    fn forward_factor(envelope: &Envelope) -> f32 {
+        match &envelope.message {
+            Message::PriceUpdate(update) => return update.forward_factor,
+            _ => panic!("not a price update"),
+        }
+    }
+
+    fn queue(capacity: usize, policy: Backpressure, lines: &[&str]) -> BoundedQueue {
+        let queue = BoundedQueue::new(capacity, policy, Arc::new(Metrics::default()));
+        for line in lines {
+            queue.push(envelope(line)).unwrap();
+        }
+        return queue;
+    }
+
+    fn drain(queue: &BoundedQueue) -> Vec<Envelope> {
+        queue.close();
+        let mut items = Vec::new();
+        while let Popped::Message(envelope) = queue.pop_within(WAIT) {
+            items.push(envelope);
+        }
+        return items;
+    }
+
+    const REQUEST: &str = "EXCHANGE_RATE_REQUEST KRAKEN BTC KRAKEN USD";
+    const WAIT: std::time::Duration = std::time::Duration::from_secs(5);
+
+    #[test]
+    fn pop_within_times_out_on_an_idle_queue_and_ends_once_closed() {
+        let queue = queue(1, Backpressure::Block, &[]);
+        let short = std::time::Duration::from_millis(10);
+        assert!(matches!(queue.pop_within(short), Popped::TimedOut));
+        queue.close();
+        assert!(matches!(queue.pop_within(short), Popped::Closed));
+    }
+
+    #[test]
+    fn drop_oldest_drops_the_oldest_update_but_never_a_request() {
+        let queue = queue(
+            2,
+            Backpressure::DropOldest,
+            &[
+                REQUEST,
+                "2017-11-01T09:42:23+00:00 KRAKEN BTC USD 1000.0 0.0009",
+                "2017-11-01T09:42:24+00:00 GDAX BTC USD 1001.0 0.0008",
+            ],
+        );
+        let items = drain(&queue);
+        assert_eq!(items.len(), 2);
+        assert!(matches!(items[0].message, Message::ExchangeRateRequest(_)));
+        assert_eq!(forward_factor(&items[1]), 1001.0);
+    }
+
+    #[test]
+    fn coalesce_replaces_a_queued_update_only_when_strictly_newer() {
+        let queue = queue(
+            2,
+            Backpressure::Coalesce,
+            &[
+                "2017-11-01T09:42:23+00:00 KRAKEN BTC USD 1000.0 0.0009",
+                "2017-11-01T09:42:23+00:00 GDAX BTC USD 1001.0 0.0008",
+                "2017-11-01T09:42:23+00:00 KRAKEN BTC USD 2000.0 0.0009",
+                "2017-11-01T09:42:24+00:00 GDAX USD BTC 0.0008 3000.0",
+            ],
+        );
+        let items = drain(&queue);
+        assert_eq!(items.len(), 2);
+        assert_eq!(forward_factor(&items[0]), 1000.0);
+        assert_eq!(forward_factor(&items[1]), 0.0008);
+    }
+
+    #[test]
+    fn coalesce_does_not_merge_across_a_request() {
+        let queue = Arc::new(queue(
+            3,
+            Backpressure::Coalesce,
+            &[
+                "2017-11-01T09:42:23+00:00 KRAKEN BTC USD 1000.0 0.0009",
+                REQUEST,
+                "USE other",
+            ],
+        ));
+        // Nothing behind the USE to merge with, so the push waits for room.
+        let pusher = Arc::clone(&queue);
+        let pushed = thread::spawn(move || {
+            pusher.push(envelope(
+                "2017-11-01T09:42:24+00:00 KRAKEN BTC USD 2000.0 0.0009",
+            ))
+        });
+        let first = match queue.pop_within(WAIT) {
+            Popped::Message(envelope) => envelope,
+            _ => panic!("expected the first update"),
+        };
+        assert_eq!(forward_factor(&first), 1000.0);
+        pushed.join().unwrap().unwrap();
+        let items = drain(&queue);
+        assert_eq!(items.len(), 3);
+        assert_eq!(forward_factor(&items[2]), 2000.0);
+    }
+
+    #[test]
+    fn coalesce_keeps_the_first_of_equal_timestamps() {
+        let batch = vec![
+            envelope("2017-11-01T09:42:23+00:00 KRAKEN BTC USD 1000.0 0.0009"),
+            envelope("2017-11-01T09:42:23+00:00 KRAKEN BTC USD 2000.0 0.0009"),
+        ];
+        let (kept, coalesced) = coalesce_updates(batch);
+        assert_eq!(coalesced, 1);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(forward_factor(&kept[0]), 1000.0);
+    }
+
+    #[test]
+    fn coalesce_keeps_the_most_recent_update_for_a_pair() {
+        let batch = vec![
+            envelope("2017-11-01T09:42:23+00:00 KRAKEN BTC USD 1000.0 0.0009"),
+            envelope("2017-11-01T09:42:25+00:00 KRAKEN USD BTC 0.0009 3000.0"),
+            envelope("2017-11-01T09:42:24+00:00 KRAKEN BTC USD 2000.0 0.0009"),
+            envelope("2017-11-01T09:42:24+00:00 GDAX BTC USD 1001.0 0.0008"),
+        ];
+        let (kept, coalesced) = coalesce_updates(batch);
+        assert_eq!(coalesced, 2);
+        assert_eq!(kept.len(), 2);
+        assert_eq!(forward_factor(&kept[0]), 0.0009);
+        assert_eq!(forward_factor(&kept[1]), 1001.0);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::Message;
+use chrono::{DateTime, FixedOffset};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/* How fast replay feeds a recorded file through the engine. Max feeds it as fast as it can
+ * be read. Factor spaces the lines out by the gaps between their timestamps, divided by the
+ * factor, so Factor(1.0) is real time and Factor(10.0) ten times faster.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplaySpeed {
+    Max,
+    Factor(f64),
+}
+
+pub fn parse_replay_speed(input: &str) -> Result<ReplaySpeed, String> {
+    let input = input.to_ascii_lowercase();
+    match input.as_str() {
+        "max" => return Ok(ReplaySpeed::Max),
+        "realtime" | "real-time" => return Ok(ReplaySpeed::Factor(1.0)),
+        _ => {}
+    }
+    let factor = input.strip_suffix('x').unwrap_or(&input);
+    match factor.parse::<f64>() {
+        Ok(f) if f.is_finite() && f > 0.0 => return Ok(ReplaySpeed::Factor(f)),
+        _ => {
+            return Err(format!(
+                "Unknown replay speed '{}'. Expected max, realtime or a factor such as 10x",
+                input
+            ))
+        }
+    }
+}
+
+pub fn replay_speed_name(speed: ReplaySpeed) -> String {
+    match speed {
+        ReplaySpeed::Max => return "max".to_string(),
+        ReplaySpeed::Factor(1.0) => return "realtime".to_string(),
+        ReplaySpeed::Factor(f) => return format!("{}x", f),
+    }
+}
+
+/* Holds each timestamped line back until its time comes, measured from the first one.
+ * A line stamped earlier than one already sent goes straight through, as do lines without a
+ * timestamp, such as requests, which keep their place right after the line before them.
+ */
+pub struct Pacer {
+    speed: ReplaySpeed,
+    origin: Option<(DateTime<FixedOffset>, Instant)>,
+}
+
+impl Pacer {
+    pub fn new(speed: ReplaySpeed) -> Pacer {
+        return Pacer {
+            speed,
+            origin: None,
+        };
+    }
+
+    pub fn wait(&mut self, message: &Message) {
+        if let Some(delay) = self.delay(message, Instant::now()) {
+            thread::sleep(delay);
+        }
+    }
+
+    fn delay(&mut self, message: &Message, now: Instant) -> Option<Duration> {
+        /* How long a message arriving at now has to be held back, if at all.
+         */
+        let factor = match self.speed {
+            ReplaySpeed::Max => return None,
+            ReplaySpeed::Factor(f) => f,
+        };
+        let timestamp = match message {
+            Message::PriceUpdate(update) => update.timestamp,
+            Message::PairRemove(removal) => removal.timestamp,
+            _ => return None,
+        };
+        let (first, started) = *self.origin.get_or_insert((timestamp, now));
+        let offset = (timestamp - first).to_std().ok()?;
+        let due = started + offset.div_f64(factor);
+        if due > now {
+            return Some(due - now);
+        }
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io_helpers::parse_line;
+
+    fn message(line: &str) -> Message {
+        return parse_line(line).unwrap().expect("a message");
+    }
+
+    #[test]
+    fn replay_speeds_parse() {
+        assert_eq!(parse_replay_speed("max"), Ok(ReplaySpeed::Max));
+        assert_eq!(parse_replay_speed("MAX"), Ok(ReplaySpeed::Max));
+        assert_eq!(parse_replay_speed("realtime"), Ok(ReplaySpeed::Factor(1.0)));
+        assert_eq!(
+            parse_replay_speed("real-time"),
+            Ok(ReplaySpeed::Factor(1.0))
+        );
+        assert_eq!(parse_replay_speed("10x"), Ok(ReplaySpeed::Factor(10.0)));
+        assert_eq!(parse_replay_speed("0.5"), Ok(ReplaySpeed::Factor(0.5)));
+        for input in &["0", "0x", "-2x", "-1", "inf", "NaNx", "fast", "x", ""] {
+            assert!(parse_replay_speed(input).is_err(), "{}", input);
+        }
+        assert_eq!(replay_speed_name(ReplaySpeed::Factor(1.0)), "realtime");
+        assert_eq!(replay_speed_name(ReplaySpeed::Factor(10.0)), "10x");
+    }
+
+    #[test]
+    fn lines_are_held_back_by_their_offset_from_the_first_divided_by_the_speed() {
+        let first = message("2017-11-01T09:00:00+00:00 KRAKEN BTC USD 1000.0 0.0009");
+        let later = message("2017-11-01T09:00:10+00:00 KRAKEN BTC USD 1001.0 0.0009");
+        let removal = message("PAIR_REMOVE 2017-11-01T09:00:20+00:00 KRAKEN BTC USD");
+        let earlier = message("2017-11-01T08:59:00+00:00 KRAKEN BTC USD 999.0 0.0009");
+        let request = message("EXCHANGE_RATE_REQUEST KRAKEN BTC KRAKEN USD");
+        let start = Instant::now();
+        let seconds = Duration::from_secs;
+
+        let mut pacer = Pacer::new(ReplaySpeed::Factor(10.0));
+        assert_eq!(pacer.delay(&first, start), None);
+        assert_eq!(pacer.delay(&later, start), Some(seconds(1)));
+        assert_eq!(pacer.delay(&later, start + seconds(1)), None);
+        assert_eq!(
+            pacer.delay(&removal, start + Duration::from_millis(500)),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(pacer.delay(&earlier, start), None);
+        assert_eq!(pacer.delay(&request, start), None);
+
+        let mut pacer = Pacer::new(ReplaySpeed::Factor(1.0));
+        pacer.delay(&first, start);
+        assert_eq!(pacer.delay(&later, start + seconds(4)), Some(seconds(6)));
+
+        let mut pacer = Pacer::new(ReplaySpeed::Max);
+        pacer.delay(&first, start);
+        assert_eq!(pacer.delay(&later, start), None);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::engine::{SharedEngine, DEFAULT_NAMESPACE};
+use crate::io_helpers::{LineParser, Output, OutputFormat, Response};
+use crate::log_helpers::{log_info, log_warn};
+use crate::metrics_helpers::Metrics;
+use std::io::{BufRead, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+pub fn serve(listen: &str, engine: Arc<SharedEngine>, format: OutputFormat) -> Result<(), String> {
+    /* Accepts connections on the given address. Every connection speaks the same line protocol
+     * as stdin: price updates and exchange rate requests, one per line. All connections share
+     * one engine, so a price update sent on one connection is visible to requests on another.
+     * Requests are answered from snapshots, so they run side by side without waiting on updates.
+     * Responses and errors are written back on the connection that sent the line.
+     * USE only changes the namespace of the connection it was sent on.
+     */
+    let listener =
+        TcpListener::bind(listen).map_err(|e| format!("Could not listen on {}: {}", listen, e))?;
+    log_info(&format!("Listening on {}", listen));
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let engine = Arc::clone(&engine);
+                thread::spawn(move || handle_connection(stream, engine, format));
+            }
+            Err(e) => log_warn(&format!("Failed to accept connection: {}", e)),
+        }
+    }
+    return Ok(());
+}
+
+fn handle_connection(stream: TcpStream, engine: Arc<SharedEngine>, format: OutputFormat) {
+    let peer = stream
+        .peer_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+    log_info(&format!("Connection from {}", peer));
+
+    let writer = match stream.try_clone() {
+        Ok(w) => w,
+        Err(e) => {
+            log_warn(&format!("Could not set up connection {}: {}", peer, e));
+            return;
+        }
+    };
+    let mut output = Output::new(format, Box::new(writer));
+    let metrics = engine.metrics();
+
+    let mut parser = LineParser::new();
+    let mut namespace = DEFAULT_NAMESPACE.to_string();
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => {
+                log_warn(&format!("Error reading from {}: {}", peer, e));
+                break;
+            }
+        };
+        let result = match parser.parse(&line) {
+            Ok(Some(message)) => engine.handle_message(&namespace, message),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        if let Ok(Some(Response::Using(name))) = &result {
+            namespace = name.clone();
+        }
+        let written = match result {
+            Ok(Some(response)) => output.write_response(&response),
+            Ok(None) => Ok(()),
+            Err(e) => {
+                Metrics::inc(&metrics.rejected_lines);
+                output.write_error(&e)
+            }
+        };
+        if written.is_err() {
+            break;
+        }
+    }
+    log_info(&format!("Connection from {} closed", peer));
+}
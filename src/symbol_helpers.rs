@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+/* How the case of exchange and currency symbols is normalised before aliases are applied.
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SymbolCase {
+    Upper,
+    Lower,
+    Preserve,
+}
+
+pub fn parse_symbol_case(input: &str) -> Result<SymbolCase, String> {
+    match input.to_ascii_lowercase().as_str() {
+        "upper" => Ok(SymbolCase::Upper),
+        "lower" => Ok(SymbolCase::Lower),
+        "preserve" => Ok(SymbolCase::Preserve),
+        _ => Err(format!(
+            "Unknown symbol case '{}'. Expected one of: upper, lower, preserve",
+            input
+        )),
+    }
+}
+
+/* Maps the symbols used by a feed to the canonical ones used in the graph, so that e.g. XBT
+ * on one exchange and BTC on another end up as the same currency and get transfer edges.
+ * Like the log level this is set at startup, and on reload, and read wherever input is parsed.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbols {
+    pub case: SymbolCase,
+    pub exchanges: BTreeMap<String, String>,
+    pub currencies: BTreeMap<String, String>,
+}
+
+impl Symbols {
+    pub const fn new() -> Symbols {
+        return Symbols {
+            case: SymbolCase::Upper,
+            exchanges: BTreeMap::new(),
+            currencies: BTreeMap::new(),
+        };
+    }
+
+    pub fn with_aliases(
+        case: SymbolCase,
+        exchanges: &BTreeMap<String, String>,
+        currencies: &BTreeMap<String, String>,
+    ) -> Result<Symbols, String> {
+        /* Builds the alias tables with both sides normalised to the given case.
+         * An alias must point straight at a canonical symbol, not at another alias.
+         */
+        let mut symbols = Symbols {
+            case,
+            exchanges: BTreeMap::new(),
+            currencies: BTreeMap::new(),
+        };
+        for (kind, aliases, table) in [
+            ("exchange", exchanges, &mut symbols.exchanges),
+            ("currency", currencies, &mut symbols.currencies),
+        ] {
+            for (alias, canonical) in aliases {
+                table.insert(apply_case(case, alias), apply_case(case, canonical));
+            }
+            for (alias, canonical) in table.iter() {
+                if table.contains_key(canonical) && canonical != alias {
+                    return Err(format!(
+                        "The {} alias {} points at {}, which is itself an alias",
+                        kind, alias, canonical
+                    ));
+                }
+            }
+        }
+        return Ok(symbols);
+    }
+
+    pub fn exchange(&self, input: &str) -> String {
+        return canonical(self.case, &self.exchanges, input);
+    }
+
+    pub fn currency(&self, input: &str) -> String {
+        return canonical(self.case, &self.currencies, input);
+    }
+}
+
+impl Default for Symbols {
+    fn default() -> Symbols {
+        return Symbols::new();
+    }
+}
+
+static SYMBOLS: RwLock<Symbols> = RwLock::new(Symbols::new());
+
+pub fn set_symbols(symbols: Symbols) {
+    if let Ok(mut current) = SYMBOLS.write() {
+        *current = symbols;
+    }
+}
+
+pub fn current_symbols() -> Symbols {
+    return match SYMBOLS.read() {
+        Ok(symbols) => symbols.clone(),
+        Err(_) => Symbols::new(),
+    };
+}
+
+pub fn canonical_exchange(input: &str) -> String {
+    return match SYMBOLS.read() {
+        Ok(symbols) => symbols.exchange(input),
+        Err(_) => input.to_string(),
+    };
+}
+
+pub fn canonical_currency(input: &str) -> String {
+    return match SYMBOLS.read() {
+        Ok(symbols) => symbols.currency(input),
+        Err(_) => input.to_string(),
+    };
+}
+
+fn canonical(case: SymbolCase, aliases: &BTreeMap<String, String>, input: &str) -> String {
+    let normalised = apply_case(case, input);
+    return match aliases.get(&normalised) {
+        Some(canonical) => canonical.clone(),
+        None => normalised,
+    };
+}
+
+fn apply_case(case: SymbolCase, input: &str) -> String {
+    match case {
+        SymbolCase::Upper => input.to_uppercase(),
+        SymbolCase::Lower => input.to_lowercase(),
+        SymbolCase::Preserve => input.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aliases(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        return pairs
+            .iter()
+            .map(|(alias, canonical)| (alias.to_string(), canonical.to_string()))
+            .collect();
+    }
+
+    #[test]
+    fn symbols_are_upper_cased_by_default() {
+        let symbols = Symbols::default();
+        assert_eq!(symbols.exchange("Kraken"), "KRAKEN");
+        assert_eq!(symbols.currency("btc"), "BTC");
+        assert_eq!(symbols.currency("BTC"), "BTC");
+
+        let preserved =
+            Symbols::with_aliases(SymbolCase::Preserve, &BTreeMap::new(), &BTreeMap::new())
+                .unwrap();
+        assert_eq!(preserved.currency("btc"), "btc");
+    }
+
+    #[test]
+    fn aliases_resolve_after_the_case_is_applied() {
+        let symbols = Symbols::with_aliases(
+            SymbolCase::Upper,
+            &aliases(&[("coinbase", "gdax")]),
+            &aliases(&[("XBT", "BTC"), ("xdg", "Doge")]),
+        )
+        .unwrap();
+        assert_eq!(symbols.exchange("Coinbase"), "GDAX");
+        assert_eq!(symbols.exchange("KRAKEN"), "KRAKEN");
+        assert_eq!(symbols.currency("xbt"), "BTC");
+        assert_eq!(symbols.currency("XDG"), "DOGE");
+        assert_eq!(symbols.currency("btc"), "BTC");
+
+        let lower = Symbols::with_aliases(
+            SymbolCase::Lower,
+            &BTreeMap::new(),
+            &aliases(&[("XBT", "BTC")]),
+        )
+        .unwrap();
+        assert_eq!(lower.currency("Xbt"), "btc");
+    }
+
+    #[test]
+    fn an_alias_of_an_alias_is_rejected() {
+        let chained = Symbols::with_aliases(
+            SymbolCase::Upper,
+            &BTreeMap::new(),
+            &aliases(&[("XBT", "BTC"), ("BITCOIN", "xbt")]),
+        );
+        assert_eq!(
+            chained,
+            Err("The currency alias BITCOIN points at XBT, which is itself an alias".to_string())
+        );
+
+        // Under preserve, coinbase and Coinbase are different symbols, so this isn't a chain.
+        let chained = Symbols::with_aliases(
+            SymbolCase::Preserve,
+            &aliases(&[("Coinbase", "GDAX"), ("CB", "coinbase")]),
+            &BTreeMap::new(),
+        );
+        assert!(chained.is_ok());
+        let chained = Symbols::with_aliases(
+            SymbolCase::Upper,
+            &aliases(&[("Coinbase", "GDAX"), ("CB", "coinbase")]),
+            &BTreeMap::new(),
+        );
+        assert!(chained.is_err());
+    }
+}